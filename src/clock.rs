@@ -0,0 +1,147 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl TimeOfDay {
+    pub fn new(hour: u8, minute: u8, second: u8) -> Option<Self> {
+        (hour < 24 && minute < 60 && second < 60).then_some(Self {
+            hour,
+            minute,
+            second,
+        })
+    }
+    pub fn minute_of_day(&self) -> u16 {
+        self.hour as u16 * 60 + self.minute as u16
+    }
+}
+
+/// Calendar date, the RTC counts years 2000 to 2099.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl Date {
+    /// Where a clock that was never set starts counting.
+    pub const EPOCH: Date = Date {
+        year: 2000,
+        month: 1,
+        day: 1,
+    };
+
+    pub fn new(year: u16, month: u8, day: u8) -> Option<Self> {
+        let date = Self { year, month, day };
+        ((2000..2100).contains(&year)
+            && (1..=12).contains(&month)
+            && (1..=date.days_in_month()).contains(&day))
+        .then_some(date)
+    }
+    fn days_in_month(&self) -> u8 {
+        match self.month {
+            2 if self.year.is_multiple_of(4) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+    /// The following day, wrapping to 2000 after 2099 like the RTC.
+    pub fn next(&self) -> Self {
+        if self.day < self.days_in_month() {
+            Self {
+                day: self.day + 1,
+                ..*self
+            }
+        } else if self.month < 12 {
+            Self {
+                month: self.month + 1,
+                day: 1,
+                ..*self
+            }
+        } else if self.year < 2099 {
+            Self {
+                year: self.year + 1,
+                month: 1,
+                day: 1,
+            }
+        } else {
+            Self::EPOCH
+        }
+    }
+    /// 0 for Monday up to 6 for Sunday.
+    pub fn weekday(&self) -> u8 {
+        // Sakamoto's method, counting January and February with the year
+        // before.
+        const T: [u16; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+        let y = self.year - (self.month < 3) as u16;
+        let sunday_first =
+            (y + y / 4 - y / 100 + y / 400 + T[self.month as usize - 1] + self.day as u16) % 7;
+        ((sunday_first + 6) % 7) as u8
+    }
+}
+
+/// Wall clock the watering schedule runs on.
+pub trait Clock {
+    fn now(&mut self) -> Option<TimeOfDay>;
+    /// Keeps the date.
+    fn set(&mut self, time: TimeOfDay) -> Result<(), ()>;
+    fn today(&mut self) -> Option<Date>;
+    /// Keeps the time of day.
+    fn set_date(&mut self, date: Date) -> Result<(), ()>;
+}
+
+#[cfg(target_os = "none")]
+impl Clock for embassy_stm32::rtc::Rtc {
+    fn now(&mut self) -> Option<TimeOfDay> {
+        let now = embassy_stm32::rtc::Rtc::now(self).ok()?;
+        TimeOfDay::new(now.hour(), now.minute(), now.second())
+    }
+    fn set(&mut self, time: TimeOfDay) -> Result<(), ()> {
+        // A clock that is not running yet starts on the epoch.
+        let date = self.today().unwrap_or(Date::EPOCH);
+        set_rtc(self, date, time)
+    }
+    fn today(&mut self) -> Option<Date> {
+        let now = embassy_stm32::rtc::Rtc::now(self).ok()?;
+        Date::new(now.year(), now.month(), now.day())
+    }
+    fn set_date(&mut self, date: Date) -> Result<(), ()> {
+        let time = Clock::now(self).unwrap_or(TimeOfDay {
+            hour: 0,
+            minute: 0,
+            second: 0,
+        });
+        set_rtc(self, date, time)
+    }
+}
+
+#[cfg(target_os = "none")]
+fn set_rtc(rtc: &mut embassy_stm32::rtc::Rtc, date: Date, time: TimeOfDay) -> Result<(), ()> {
+    use embassy_stm32::rtc::{DateTime, DayOfWeek};
+    let weekday = match date.weekday() {
+        0 => DayOfWeek::Monday,
+        1 => DayOfWeek::Tuesday,
+        2 => DayOfWeek::Wednesday,
+        3 => DayOfWeek::Thursday,
+        4 => DayOfWeek::Friday,
+        5 => DayOfWeek::Saturday,
+        _ => DayOfWeek::Sunday,
+    };
+    let datetime = DateTime::from(
+        date.year,
+        date.month,
+        date.day,
+        weekday,
+        time.hour,
+        time.minute,
+        time.second,
+    )
+    .map_err(|_| ())?;
+    rtc.set_datetime(datetime).map_err(|_| ())
+}
@@ -1,34 +1,76 @@
-use defmt::info;
+use crate::clock::{Date, TimeOfDay};
+use crate::gcode::{parse_gcode, Gcode};
+use crate::schedule::Entry;
+use core::fmt::{self, Write};
+use heapless::String;
 use nom::{
     branch::{alt, permutation},
-    bytes::complete::{is_a, tag_no_case},
+    bytes::complete::{is_a, tag, tag_no_case},
     character::complete::{digit1, multispace0},
-    combinator::{all_consuming, map_res, opt, value},
+    combinator::{all_consuming, map, map_res, opt, value},
+    multi::fold_many0,
     sequence::{preceded, terminated, tuple},
     IResult, Parser,
 };
+use serde::{Deserialize, Serialize};
 
-#[derive(PartialEq, Eq, Debug, Clone)]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub struct UnsignSet {
     pub x: Option<u32>,
     pub y: Option<u32>,
     pub z: Option<u32>,
 }
 
-#[derive(PartialEq, Eq, Debug, Clone)]
+/// Positions and distances in micrometers, typed as millimeters with up to
+/// three decimals.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
 pub struct Set {
     pub x: Option<i32>,
     pub y: Option<i32>,
     pub z: Option<i32>,
 }
 
-#[derive(PartialEq, Eq, Debug, Clone)]
+/// Axes selected by a command, `home` alone selects all of them.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(default)]
+pub struct Axes {
+    pub x: bool,
+    pub y: bool,
+    pub z: bool,
+}
+
+/// Micrometers printed as millimeters, trailing zero decimals dropped:
+/// `12500` shows as `12.5`, `-3000` as `-3`. Honours width and alignment.
+pub struct Mm(pub i32);
+
+impl fmt::Display for Mm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut buf = String::<16>::new();
+        let um = self.0.unsigned_abs();
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let (int, frac) = (um / 1000, um % 1000);
+        if frac == 0 {
+            write!(buf, "{}{}", sign, int)?;
+        } else if frac.is_multiple_of(100) {
+            write!(buf, "{}{}.{}", sign, int, frac / 100)?;
+        } else if frac.is_multiple_of(10) {
+            write!(buf, "{}{}.{:02}", sign, int, frac / 10)?;
+        } else {
+            write!(buf, "{}{}.{:03}", sign, int, frac)?;
+        }
+        f.pad(&buf)
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub enum Cmd {
     Goto(Set),
     Move(Set),
+    Linear(Set),
     SpeedMin(UnsignSet),
     SpeedMax(UnsignSet),
     SpeedAccel(UnsignSet),
+    SpeedJerk(UnsignSet),
     StepPerMM(UnsignSet),
     AddPos(Set, Option<u32>),
     WaterDuration(Option<u32>, u32),
@@ -37,20 +79,125 @@ pub enum Cmd {
     PumpOn,
     PumpOff,
     ListPos,
+    Where,
+    Status,
     Start,
     Stop,
-    Home,
+    Home(Axes),
+    HomeOffset(Set),
+    HomeDir(Set),
+    HomeSpeed(UnsignSet),
+    LimitMin(Set),
+    LimitMax(Set),
+    ListLimit,
+    SaveConfig,
+    ResetConfig,
+    TimeSet(TimeOfDay),
+    TimeGet,
+    DateSet(Date),
+    DateGet,
+    ScheduleAdd(Entry),
+    ScheduleDel(u32),
+    ScheduleList,
+    GroupSet(u32, u8),
+    EStop,
+    Reset,
     Help,
+    /// One line of G-code, see `gcode`.
+    Gcode(Gcode),
+    /// Push `event`s to this link. Answered by the link, not the controller.
+    Subscribe,
+    Unsubscribe,
+    /// Newest watering history entries, 10 by default.
+    LogShow(Option<u32>),
+    LogClear,
 }
-fn parse_i32(input: &str) -> IResult<&str, i32> {
+
+/// Failure class of a command, the number printed in `[ERR <code> <msg>]`.
+/// Codes are part of the console protocol, only ever append new ones.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ErrorCode {
+    /// The line is not a command.
+    Parse = 1,
+    /// `add pos` without all three coordinates.
+    MissingAxis = 2,
+    /// No position or schedule entry with that id.
+    OutOfRange = 3,
+    /// Position list or schedule table full.
+    Full = 4,
+    /// Applied, but the EEPROM write failed so it is lost on reset.
+    Storage = 5,
+    /// Target outside the soft travel limits.
+    Travel = 6,
+    /// E-stop latched, or its input still open on `reset`.
+    Fault = 7,
+    /// Position unknown, home first.
+    NotHomed = 8,
+    /// Refused while farming is on.
+    Busy = 9,
+    /// Homing did not find or leave the endstop.
+    Home = 10,
+    /// Real time clock not running or not set.
+    Clock = 11,
+    /// The answer does not fit the reply buffer of the protocol in use.
+    TooLong = 12,
+}
+
+/// Error reply of a command, printed as `[ERR 3 index out of range]` in
+/// place of `[OK]`.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct CmdError {
+    pub code: ErrorCode,
+    pub msg: String<64>,
+}
+
+impl CmdError {
+    /// `msg` is cut off at 64 bytes.
+    pub fn new(code: ErrorCode, msg: &str) -> Self {
+        let mut err = Self {
+            code,
+            msg: String::new(),
+        };
+        for c in msg.chars() {
+            if err.msg.push(c).is_err() {
+                break;
+            }
+        }
+        err
+    }
+}
+
+impl fmt::Display for CmdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[ERR {} {}]", self.code as u8, self.msg)
+    }
+}
+/// Millimeters with up to three decimals, e.g. `-12.5` or `.5`, as
+/// micrometers.
+pub fn parse_mm(input: &str) -> IResult<&str, i32> {
     preceded(
         multispace0,
-        alt((
-            map_res(preceded(is_a("-"), digit1), |s: &str| {
-                s.parse().map(|n: i32| -n)
-            }),
-            map_res(digit1, |s: &str| s.parse()),
-        )),
+        map_res(
+            tuple((
+                opt(is_a("-")),
+                alt((
+                    tuple((digit1, opt(preceded(tag("."), digit1)))),
+                    preceded(tag("."), digit1).map(|frac| ("0", Some(frac))),
+                )),
+            )),
+            |(sign, (int, frac)): (Option<&str>, (&str, Option<&str>))| {
+                let frac = frac.unwrap_or("");
+                if frac.len() > 3 {
+                    return Err(());
+                }
+                let mut um = int.parse::<i64>().map_err(|_| ())? * 1000;
+                for (digit, scale) in frac.bytes().zip([100, 10, 1]) {
+                    um += (digit - b'0') as i64 * scale;
+                }
+                let um = if sign.is_some() { -um } else { um };
+                i32::try_from(um).map_err(|_| ())
+            },
+        ),
     )
     .parse(input)
 }
@@ -66,13 +213,13 @@ fn parse_u32(input: &str) -> IResult<&str, u32> {
 }
 
 fn parse_ix(input: &str) -> IResult<&str, i32> {
-    preceded(multispace0, preceded(tag_no_case("x"), parse_i32)).parse(input)
+    preceded(multispace0, preceded(tag_no_case("x"), parse_mm)).parse(input)
 }
 fn parse_iy(input: &str) -> IResult<&str, i32> {
-    preceded(multispace0, preceded(tag_no_case("y"), parse_i32)).parse(input)
+    preceded(multispace0, preceded(tag_no_case("y"), parse_mm)).parse(input)
 }
 fn parse_iz(input: &str) -> IResult<&str, i32> {
-    preceded(multispace0, preceded(tag_no_case("z"), parse_i32)).parse(input)
+    preceded(multispace0, preceded(tag_no_case("z"), parse_mm)).parse(input)
 }
 fn parse_3(input: &str) -> IResult<&str, Set> {
     permutation((parse_ix, parse_iy, parse_iz))
@@ -81,7 +228,7 @@ fn parse_3(input: &str) -> IResult<&str, Set> {
         .or(permutation((parse_iy, parse_iz, parse_ix)).map(|(y, z, x)| (x, y, z)))
         .or(permutation((parse_iz, parse_iy, parse_ix)).map(|(z, y, x)| (x, y, z)))
         .or(permutation((parse_iz, parse_ix, parse_iy)).map(|(z, x, y)| (x, y, z)))
-        .or(tuple((parse_i32, parse_i32, parse_i32)))
+        .or(tuple((parse_mm, parse_mm, parse_mm)))
         .map(|(x, y, z)| Set {
             x: Some(x),
             y: Some(y),
@@ -121,104 +268,376 @@ fn parse_set(input: &str) -> IResult<&str, Set> {
         })
 }
 
+/// Speeds and ratios stay whole numbers, decimals are refused.
 fn parse_set_unsigned(input: &str) -> IResult<&str, UnsignSet> {
-    fn lt0(x: i32) -> bool {
-        x < 0
+    fn bad(um: i32) -> bool {
+        um < 0 || um % 1000 != 0
     }
     map_res(parse_3.or(parse_2).or(parse_1), |set| {
-        if set.x.is_some_and(lt0) || set.y.is_some_and(lt0) || set.z.is_some_and(lt0) {
+        if set.x.is_some_and(bad) || set.y.is_some_and(bad) || set.z.is_some_and(bad) {
             Err(())
         } else {
             Ok({
                 UnsignSet {
-                    x: set.x.map(|v| v as u32),
-                    y: set.y.map(|v| v as u32),
-                    z: set.z.map(|v| v as u32),
+                    x: set.x.map(|v| v as u32 / 1000),
+                    y: set.y.map(|v| v as u32 / 1000),
+                    z: set.z.map(|v| v as u32 / 1000),
                 }
             })
         }
     })
     .parse(input)
 }
-pub fn parse_cmd(input: &str) -> IResult<&str, Cmd> {
+fn parse_axes(input: &str) -> IResult<&str, Axes> {
+    map(
+        fold_many0(
+            preceded(
+                multispace0,
+                alt((tag_no_case("x"), tag_no_case("y"), tag_no_case("z"))),
+            ),
+            Axes::default,
+            |mut axes, axis: &str| {
+                match axis {
+                    "x" | "X" => axes.x = true,
+                    "y" | "Y" => axes.y = true,
+                    _ => axes.z = true,
+                }
+                axes
+            },
+        ),
+        |axes| {
+            if axes == Axes::default() {
+                Axes {
+                    x: true,
+                    y: true,
+                    z: true,
+                }
+            } else {
+                axes
+            }
+        },
+    )
+    .parse(input)
+}
+
+fn parse_control(input: &str) -> IResult<&str, Cmd> {
     alt((
         all_consuming(terminated(
-            preceded(tag_no_case("goto"), parse_set).map(Cmd::Goto),
+            value(Cmd::PumpOn, tag_no_case("pump on")),
             multispace0,
         )),
         all_consuming(terminated(
-            preceded(tag_no_case("move"), parse_set).map(Cmd::Move),
+            value(Cmd::PumpOff, tag_no_case("pump off")),
             multispace0,
         )),
         all_consuming(terminated(
-            preceded(tag_no_case("speed max"), parse_set_unsigned).map(Cmd::SpeedMax),
+            value(Cmd::Start, tag_no_case("start")),
             multispace0,
         )),
         all_consuming(terminated(
-            preceded(tag_no_case("speed min"), parse_set_unsigned).map(Cmd::SpeedMin),
+            value(Cmd::Stop, tag_no_case("stop")),
             multispace0,
         )),
         all_consuming(terminated(
-            preceded(tag_no_case("speed acc"), parse_set_unsigned).map(Cmd::SpeedAccel),
+            value(Cmd::EStop, tag_no_case("estop")),
             multispace0,
         )),
         all_consuming(terminated(
-            preceded(tag_no_case("step_per_mm"), parse_set_unsigned).map(Cmd::StepPerMM),
+            value(Cmd::Reset, tag_no_case("reset")),
             multispace0,
         )),
         all_consuming(terminated(
-            preceded(tag_no_case("add pos"), parse_3).map(|pos| Cmd::AddPos(pos, None)),
+            value(Cmd::Subscribe, tag_no_case("subscribe")),
             multispace0,
         )),
         all_consuming(terminated(
-            preceded(tag_no_case("add pos"), tuple((parse_3, opt(parse_u32))))
-                .map(|(pos, dur)| Cmd::AddPos(pos, dur)),
+            value(Cmd::Unsubscribe, tag_no_case("unsubscribe")),
+            multispace0,
+        )),
+    ))
+    .parse(input)
+}
+
+fn parse_query(input: &str) -> IResult<&str, Cmd> {
+    alt((
+        all_consuming(terminated(
+            value(Cmd::ListPos, tag_no_case("list pos")),
             multispace0,
         )),
         all_consuming(terminated(
-            preceded(
-                tag_no_case("water duration"),
-                tuple((opt(parse_u32), parse_u32)),
-            )
-            .map(|(id, dur)| Cmd::WaterDuration(id, dur)),
+            value(Cmd::Where, tag_no_case("where")),
             multispace0,
         )),
         all_consuming(terminated(
-            preceded(tag_no_case("del pos"), parse_u32).map(Cmd::DelPos),
+            value(Cmd::Status, tag_no_case("status")),
             multispace0,
         )),
         all_consuming(terminated(
-            preceded(tag_no_case("repeat duration"), parse_u32).map(Cmd::RepeatDur),
+            preceded(tag_no_case("log show"), opt(parse_u32)).map(Cmd::LogShow),
             multispace0,
         )),
         all_consuming(terminated(
-            value(Cmd::ListPos, tag_no_case("list pos")),
+            value(Cmd::LogClear, tag_no_case("log clear")),
             multispace0,
         )),
+    ))
+    .parse(input)
+}
+
+fn parse_home(input: &str) -> IResult<&str, Cmd> {
+    alt((
         all_consuming(terminated(
-            value(Cmd::PumpOn, tag_no_case("pump on")),
+            preceded(tag_no_case("home offset"), parse_set).map(Cmd::HomeOffset),
             multispace0,
         )),
         all_consuming(terminated(
-            value(Cmd::PumpOff, tag_no_case("pump off")),
+            preceded(tag_no_case("home dir"), parse_set).map(Cmd::HomeDir),
             multispace0,
         )),
         all_consuming(terminated(
-            value(Cmd::Start, tag_no_case("start")),
+            preceded(tag_no_case("home speed"), parse_set_unsigned).map(Cmd::HomeSpeed),
             multispace0,
         )),
         all_consuming(terminated(
-            value(Cmd::Stop, tag_no_case("stop")),
+            preceded(tag_no_case("home"), parse_axes).map(Cmd::Home),
             multispace0,
         )),
+    ))
+    .parse(input)
+}
+
+fn parse_limit(input: &str) -> IResult<&str, Cmd> {
+    alt((
         all_consuming(terminated(
-            value(Cmd::Home, tag_no_case("home")),
+            preceded(tag_no_case("limit min"), parse_set).map(Cmd::LimitMin),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("limit max"), parse_set).map(Cmd::LimitMax),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            value(Cmd::ListLimit, tag_no_case("list limit")),
+            multispace0,
+        )),
+    ))
+    .parse(input)
+}
+
+fn parse_config(input: &str) -> IResult<&str, Cmd> {
+    alt((
+        all_consuming(terminated(
+            value(Cmd::SaveConfig, tag_no_case("save config")),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            value(Cmd::ResetConfig, tag_no_case("reset config")),
+            multispace0,
+        )),
+    ))
+    .parse(input)
+}
+
+/// `hh:mm` or `hh:mm:ss`
+fn parse_time(input: &str) -> IResult<&str, TimeOfDay> {
+    map_res(
+        preceded(
+            multispace0,
+            tuple((
+                digit1,
+                preceded(tag(":"), digit1),
+                opt(preceded(tag(":"), digit1)),
+            )),
+        ),
+        |(h, m, s): (&str, &str, Option<&str>)| {
+            let h = h.parse().map_err(|_| ())?;
+            let m = m.parse().map_err(|_| ())?;
+            let s = s.map_or(Ok(0), |s| s.parse()).map_err(|_| ())?;
+            TimeOfDay::new(h, m, s).ok_or(())
+        },
+    )
+    .parse(input)
+}
+
+/// `yyyy-mm-dd`
+fn parse_date(input: &str) -> IResult<&str, Date> {
+    map_res(
+        preceded(
+            multispace0,
+            tuple((
+                digit1,
+                preceded(tag("-"), digit1),
+                preceded(tag("-"), digit1),
+            )),
+        ),
+        |(y, m, d): (&str, &str, &str)| {
+            let y = y.parse().map_err(|_| ())?;
+            let m = m.parse().map_err(|_| ())?;
+            let d = d.parse().map_err(|_| ())?;
+            Date::new(y, m, d).ok_or(())
+        },
+    )
+    .parse(input)
+}
+
+fn parse_u8(input: &str) -> IResult<&str, u8> {
+    map_res(parse_u32, u8::try_from).parse(input)
+}
+
+/// Optional `group <id>` suffix, group 0 when absent.
+fn parse_group(input: &str) -> IResult<&str, u8> {
+    opt(preceded(
+        preceded(multispace0, tag_no_case("group")),
+        parse_u8,
+    ))
+    .map(|group| group.unwrap_or(0))
+    .parse(input)
+}
+
+fn parse_every(input: &str) -> IResult<&str, Entry> {
+    map_res(
+        tuple((
+            parse_u32,
+            opt(tuple((
+                preceded(multispace0, tag_no_case("from")),
+                parse_time,
+                preceded(multispace0, tag_no_case("to")),
+                parse_time,
+            ))),
+            parse_group,
+        )),
+        |(every, window, group)| {
+            let (start, end) = window.map_or((0, 24 * 60 - 1), |(_, from, _, to)| {
+                (from.minute_of_day(), to.minute_of_day())
+            });
+            match u16::try_from(every) {
+                Ok(every) if every > 0 && start <= end => Ok(Entry {
+                    start,
+                    end,
+                    every,
+                    group,
+                }),
+                _ => Err(()),
+            }
+        },
+    )
+    .parse(input)
+}
+
+fn parse_schedule(input: &str) -> IResult<&str, Cmd> {
+    alt((
+        all_consuming(terminated(
+            preceded(tag_no_case("time set"), parse_time).map(Cmd::TimeSet),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            value(Cmd::TimeGet, tag_no_case("time get")),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("date set"), parse_date).map(Cmd::DateSet),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            value(Cmd::DateGet, tag_no_case("date get")),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("schedule at"), tuple((parse_time, parse_group)))
+                .map(|(t, group)| Cmd::ScheduleAdd(Entry::at(t.minute_of_day(), group))),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("schedule every"), parse_every).map(Cmd::ScheduleAdd),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("schedule del"), parse_u32).map(Cmd::ScheduleDel),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            value(Cmd::ScheduleList, tag_no_case("schedule list")),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("group set"), tuple((parse_u32, parse_u8)))
+                .map(|(id, group)| Cmd::GroupSet(id, group)),
+            multispace0,
+        )),
+    ))
+    .parse(input)
+}
+
+pub fn parse_cmd(input: &str) -> IResult<&str, Cmd> {
+    alt((
+        all_consuming(terminated(
+            preceded(tag_no_case("goto"), parse_set).map(Cmd::Goto),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("move"), parse_set).map(Cmd::Move),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("linear"), parse_set).map(Cmd::Linear),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("speed max"), parse_set_unsigned).map(Cmd::SpeedMax),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("speed min"), parse_set_unsigned).map(Cmd::SpeedMin),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("speed acc"), parse_set_unsigned).map(Cmd::SpeedAccel),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("speed jerk"), parse_set_unsigned).map(Cmd::SpeedJerk),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("step_per_mm"), parse_set_unsigned).map(Cmd::StepPerMM),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("add pos"), parse_3).map(|pos| Cmd::AddPos(pos, None)),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("add pos"), tuple((parse_3, opt(parse_u32))))
+                .map(|(pos, dur)| Cmd::AddPos(pos, dur)),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(
+                tag_no_case("water duration"),
+                tuple((opt(parse_u32), parse_u32)),
+            )
+            .map(|(id, dur)| Cmd::WaterDuration(id, dur)),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("del pos"), parse_u32).map(Cmd::DelPos),
+            multispace0,
+        )),
+        all_consuming(terminated(
+            preceded(tag_no_case("repeat duration"), parse_u32).map(Cmd::RepeatDur),
             multispace0,
         )),
+        parse_query,
+        parse_control,
+        parse_home,
+        parse_limit,
+        parse_config,
+        parse_schedule,
         all_consuming(terminated(
             value(Cmd::Help, tag_no_case("help")),
             multispace0,
         )),
+        parse_gcode.map(Cmd::Gcode),
     ))
     .parse(input)
 }
@@ -1,229 +1,1647 @@
-use crate::command::Cmd;
-use crate::{pump::Pump, stepper::Stepper, storage::Storage};
-use core::fmt::Write;
-use defmt::info;
+use crate::clock::Clock;
+use crate::command::{Axes, Cmd, CmdError, ErrorCode, Mm, Set};
+use crate::event::{self, Event, Fault};
+use crate::gcode::{Gcode, Modal};
+use crate::hal::{I2cBus, Input, Pin, Rtc, StepOut};
+use crate::history::{self, History};
+use crate::pump::Pump;
+use crate::schedule::{self, EntryV1, Table, MAX_ENTRIES};
+use crate::stepper::{self, AxisConfig, AxisConfigV1, HomeError, Stepper, ESTOP, HALT};
+use crate::storage::{crc16, Storage, CRC_INIT};
+use core::fmt::{self, Write};
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, Ordering};
 use embassy_sync::signal::Signal;
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex as Raw, channel::Channel};
-use embassy_time::{Duration, Timer};
-use futures::future::join;
-use heapless::{String, Vec};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::blocking::i2c::{Write as I2cWrite, WriteRead};
+use futures::future::{join, join3, select, Either};
+use heapless::{Deque, String, Vec};
 use serde::{Deserialize, Serialize};
 
+type Axis = Stepper<Pin, StepOut, Input>;
+
+const MAX_POSITIONS: usize = 100;
+/// Marks page 0 as a position table header.
+const TABLE_MAGIC: [u8; 4] = *b"MUSH";
+/// Layout of the position table, see `decode_position` for the older ones.
+const TABLE_VERSION: u8 = 3;
+/// EEPROM page holding the travel limits, past the position list.
+const LIMITS_PAGE: u8 = 101;
+const LIMITS_VERSION: u8 = 1;
+/// First of three EEPROM pages holding the x, y and z motion parameters.
+const CONFIG_PAGE: u8 = 102;
+/// Bumped whenever `AxisConfig` changes shape.
+const CONFIG_VERSION: u8 = 2;
+/// EEPROM page holding the schedule state, after the three config pages.
+const SCHEDULE_PAGE: u8 = 105;
+const SCHEDULE_VERSION: u8 = 1;
+/// Watering time of positions added without one.
+const DEFAULT_DUR_MS: u32 = 1000;
+/// First of four EEPROM pages holding the time-of-day schedule table.
+const TABLE_PAGE: u8 = 106;
+const TABLE_PAGES: u8 = 4;
+/// Version 1 entries had no group.
+const TABLE_RECORD_VERSION: u8 = 2;
+
 static CH: Channel<Raw, Cmd, 10> = Channel::new();
-static CH_R: Signal<Raw, String<5000>> = Signal::new();
+static CH_R: Signal<Raw, Reply> = Signal::new();
+/// Wakes the idle controller so a fault latched outside a command is acted on.
+static FAULT: Signal<Raw, ()> = Signal::new();
+
+/// Whether the hardware E-stop loop is currently open.
+pub static ESTOP_INPUT: AtomicBool = AtomicBool::new(false);
 
+/// Coordinates in micrometers.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct WateringPosition {
     pub x: i32,
     pub y: i32,
     pub z: i32,
     pub dur_ms: u32,
+    pub group: u8,
+}
+
+/// Successful outcome of a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Answer {
+    /// Free text, empty for most commands.
+    Text(String<5000>),
+    /// `list pos`, kept structured for the machine protocols.
+    Positions(Vec<WateringPosition, MAX_POSITIONS>),
+    /// `log show`, newest cycle first.
+    History(Vec<history::Entry, { history::MAX_SHOW }>),
+}
+
+impl Answer {
+    /// The answer as printed on the console.
+    pub fn into_text(self) -> String<5000> {
+        match self {
+            Answer::Text(text) => text,
+            answer => {
+                let mut text = String::new();
+                write!(&mut text, "{}", answer).ok();
+                text
+            }
+        }
+    }
+}
+
+impl Default for Answer {
+    fn default() -> Self {
+        Answer::Text(String::new())
+    }
+}
+
+impl fmt::Display for Answer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Answer::Text(text) => f.write_str(text),
+            Answer::Positions(list) => {
+                for (id, pos) in list.iter().enumerate() {
+                    writeln!(
+                        f,
+                        "{:2}: ({:>8}, {:>8}, {:>8}) {:5}ms group {}",
+                        id,
+                        Mm(pos.x),
+                        Mm(pos.y),
+                        Mm(pos.z),
+                        pos.dur_ms,
+                        pos.group
+                    )?;
+                }
+                Ok(())
+            }
+            Answer::History(list) => {
+                for entry in list {
+                    write!(f, "#{:<5} ", entry.seq)?;
+                    match entry.date {
+                        Some(d) => write!(f, "{:04}-{:02}-{:02} ", d.year, d.month, d.day)?,
+                        None => write!(f, "---------- ")?,
+                    }
+                    match entry.at {
+                        Some(t) => write!(f, "{:02}:{:02}:{:02}", t.hour, t.minute, t.second)?,
+                        None => write!(f, "--:--:--")?,
+                    }
+                    let up = entry.uptime_s;
+                    write!(
+                        f,
+                        " up {}d {:02}:{:02}:{:02} watered {}/{} pump {}ms",
+                        up / 86400,
+                        up / 3600 % 24,
+                        up / 60 % 60,
+                        up % 60,
+                        entry.watered,
+                        entry.planned,
+                        entry.pump_ms
+                    )?;
+                    match entry.fault {
+                        Some(Fault::EStop) => writeln!(f, " e-stop")?,
+                        Some(Fault::Home) => writeln!(f, " home failed")?,
+                        None => writeln!(f)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// What a command answers, or why it failed.
+pub type Reply = Result<Answer, CmdError>;
+
+/// `WateringPosition` as stored by table layouts 0 and 1, in millimeters.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct WateringPositionV1 {
+    x: i32,
+    y: i32,
+    z: i32,
+    dur_ms: u32,
+}
+
+impl From<WateringPositionV1> for WateringPosition {
+    fn from(p: WateringPositionV1) -> Self {
+        Self {
+            x: p.x.saturating_mul(1000),
+            y: p.y.saturating_mul(1000),
+            z: p.z.saturating_mul(1000),
+            dur_ms: p.dur_ms,
+            group: 0,
+        }
+    }
+}
+
+/// `WateringPosition` as stored by table layout 2, in millimeters.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct WateringPositionV2 {
+    x: i32,
+    y: i32,
+    z: i32,
+    dur_ms: u32,
+    group: u8,
+}
+
+impl From<WateringPositionV2> for WateringPosition {
+    fn from(p: WateringPositionV2) -> Self {
+        Self {
+            x: p.x.saturating_mul(1000),
+            y: p.y.saturating_mul(1000),
+            z: p.z.saturating_mul(1000),
+            dur_ms: p.dur_ms,
+            group: p.group,
+        }
+    }
+}
+
+/// Outcome of the last position table restore or backup, for `status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TableHealth {
+    Restored,
+    RestoreFailed,
+    Saved,
+    SaveFailed,
+}
+
+impl TableHealth {
+    fn saved(result: Result<(), ()>) -> Self {
+        match result {
+            Ok(()) => Self::Saved,
+            Err(()) => Self::SaveFailed,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Restored => "restored",
+            Self::RestoreFailed => "restore failed",
+            Self::Saved => "saved",
+            Self::SaveFailed => "save failed",
+        }
+    }
+}
+
+/// Position list and schedule state, shared by the schedule and idle loops.
+struct Farm {
+    positions: Vec<WateringPosition, MAX_POSITIONS>,
+    enabled: bool,
+    repeat_duration: Duration,
+    table: Table,
+    table_health: TableHealth,
+    /// When the schedule wakes up next.
+    next_repeat: Option<Instant>,
+    /// Minute of the day the schedule was last checked, `None` once the
+    /// clock is set so a jump does not fire the minutes in between.
+    last_minute: Option<u16>,
+    /// Id of the position being watered, in the list as the cycle started.
+    cycle_pos: Option<usize>,
+    gcode: Modal,
+    history: History,
+}
+
+/// Page 0 of the position table: magic, layout version, record count, crc of
+/// the record pages and a crc of the header itself.
+struct TableHeader {
+    version: u8,
+    count: u8,
+    crc: u16,
+}
+
+impl TableHeader {
+    fn to_page(&self) -> [u8; 32] {
+        let mut page = [0; 32];
+        page[..4].copy_from_slice(&TABLE_MAGIC);
+        page[4] = self.version;
+        page[5] = self.count;
+        page[6..8].copy_from_slice(&self.crc.to_le_bytes());
+        let own = crc16(CRC_INIT, &page[..8]);
+        page[8..10].copy_from_slice(&own.to_le_bytes());
+        page
+    }
+
+    fn from_page(page: &[u8; 32]) -> Option<Self> {
+        let own = u16::from_le_bytes([page[8], page[9]]);
+        if page[..4] != TABLE_MAGIC || crc16(CRC_INIT, &page[..8]) != own {
+            return None;
+        }
+        Some(Self {
+            version: page[4],
+            count: page[5],
+            crc: u16::from_le_bytes([page[6], page[7]]),
+        })
+    }
+}
+
+/// Travel limits in micrometers. `version` goes last: records written in
+/// millimeters before it existed decode with the zero padding as version 0.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Limits {
+    min: [i32; 3],
+    max: [i32; 3],
+    version: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct ConfigRecord {
+    version: u8,
+    axis: AxisConfig,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct ConfigRecordV1 {
+    version: u8,
+    axis: AxisConfigV1,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct ScheduleRecord {
+    version: u8,
+    enabled: bool,
+    repeat_ms: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct TableRecord {
+    version: u8,
+    entries: Table,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct TableRecordV1 {
+    version: u8,
+    entries: Vec<EntryV1, MAX_ENTRIES>,
+}
+
+/// Cut step pulses and the pump right away and latch the fault state until
+/// `reset`.
+pub fn emergency_stop() {
+    ESTOP.store(true, Ordering::Relaxed);
+    FAULT.signal(());
+}
+
+/// A console's end of the command queue. The console reads on while the
+/// controller works: one command is in flight, up to `N` more wait here, each
+/// with a `T` telling how its reply goes out.
+pub struct Client<T, const N: usize> {
+    pending: Option<T>,
+    queued: Deque<(Cmd, T), N>,
+}
+
+impl<T, const N: usize> Client<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            pending: None,
+            queued: Deque::new(),
+        }
+    }
+
+    /// Hand `cmd` to the controller, or queue it behind the one in flight.
+    pub fn submit(&mut self, cmd: Cmd, to: T) -> Result<(), CmdError> {
+        // The controller only reads the queue between moves, so stops reach
+        // the steppers directly, ahead of whatever is queued.
+        match cmd {
+            Cmd::Stop => HALT.store(true, Ordering::Relaxed),
+            Cmd::EStop => emergency_stop(),
+            _ => (),
+        }
+        let full = || CmdError::new(ErrorCode::Full, "command queue full");
+        if self.pending.is_some() {
+            return self.queued.push_back((cmd, to)).map_err(|_| full());
+        }
+        CH.try_send(cmd).map_err(|_| full())?;
+        self.pending = Some(to);
+        Ok(())
+    }
+
+    /// The reply to the command in flight, then the next queued one goes
+    /// out. Never resolves while none is in flight.
+    pub async fn reply(&mut self) -> (Reply, T) {
+        if self.pending.is_none() {
+            return core::future::pending().await;
+        }
+        let reply = CH_R.wait().await;
+        let to = self.pending.take().unwrap();
+        if let Some((cmd, next)) = self.queued.pop_front() {
+            // Only one command is ever in the channel, and the controller
+            // has just taken it.
+            CH.try_send(cmd).ok();
+            self.pending = Some(next);
+        }
+        (reply, to)
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_none()
+    }
 }
 
-pub async fn send_msg(cmd: Cmd) -> String<5000> {
-    CH.send(cmd).await;
-    CH_R.wait().await
+impl<T, const N: usize> Default for Client<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[embassy_executor::task]
 pub async fn run(
-    mut x: Stepper<'static>,
-    mut y: Stepper<'static>,
-    mut z: Stepper<'static>,
-    mut storage: Storage,
-    mut pump: Pump,
+    mut x: Axis,
+    mut y: Axis,
+    mut z: Axis,
+    mut storage: Storage<I2cBus>,
+    mut pump: Pump<Pin>,
+    mut clock: Rtc,
 ) {
-    let mut positions = Vec::<WateringPosition, 100>::new();
-    let mut schedule_enabled = true;
-    let mut repeat_duration = Duration::from_secs(1);
-    let default_dur_ms = 1000;
+    let mut farm = Farm {
+        positions: Vec::new(),
+        enabled: true,
+        repeat_duration: Duration::from_secs(1),
+        table: Table::new(),
+        table_health: TableHealth::RestoreFailed,
+        next_repeat: None,
+        last_minute: None,
+        cycle_pos: None,
+        gcode: Modal::default(),
+        history: History::restore(&mut storage),
+    };
 
     if let Ok(list) = restore(&mut storage).await {
-        positions = list;
+        farm.positions = list;
+        farm.table_health = TableHealth::Restored;
         info!("Restored");
     } else {
         info!("Restore Error");
     }
+    if restore_limits(&mut storage, &mut x, &mut y, &mut z).is_err() {
+        info!("Restore limits Error");
+    }
+    if restore_config(&mut storage, &mut x, &mut y, &mut z).is_err() {
+        info!("Restore config Error");
+    }
+    if let Ok(record) = restore_schedule(&mut storage) {
+        farm.enabled = record.enabled;
+        farm.repeat_duration = Duration::from_millis(record.repeat_ms.into());
+    } else {
+        info!("Restore schedule Error");
+    }
+    if let Ok(entries) = restore_table(&mut storage) {
+        farm.table = entries;
+    } else {
+        info!("Restore table Error");
+    }
+
+    let all = Axes {
+        x: true,
+        y: true,
+        z: true,
+    };
+    if let Err(e) = home(&mut x, &mut y, &mut z, all).await {
+        info!("Home Error {}", e);
+        farm.enabled = false;
+    }
 
     loop {
-        while schedule_enabled {
+        while farm.enabled {
             pump.off();
-            info!("repeat");
-            Timer::after(repeat_duration).await;
-            match CH.try_receive() {
-                Ok(Cmd::Stop) => {
-                    CH_R.signal(String::new());
-                    schedule_enabled = false;
-                    break;
+            // Without a time-of-day table the cycle simply repeats over all
+            // positions, otherwise it waters the groups whose entries fire.
+            let wait = if farm.table.is_empty() {
+                info!("repeat");
+                farm.repeat_duration
+            } else {
+                Duration::from_secs(1)
+            };
+            let until = Instant::now() + wait;
+            farm.next_repeat = Some(until);
+            let axes = [&mut x, &mut y, &mut z];
+            if !farm_wait(until, &mut farm, &mut storage, &mut clock, axes, &mut pump).await {
+                break;
+            }
+            let (due, groups) = if farm.table.is_empty() {
+                (true, None)
+            } else {
+                match Clock::now(&mut clock).map(|t| t.minute_of_day()) {
+                    Some(minute) if farm.last_minute != Some(minute) => {
+                        // A cycle can outlast a minute, the entries that
+                        // fired meanwhile are caught up on in one cycle.
+                        let groups = match farm.last_minute {
+                            Some(last) => schedule::due_groups_since(&farm.table, last, minute),
+                            None => schedule::due_groups(&farm.table, minute),
+                        };
+                        farm.last_minute = Some(minute);
+                        (!groups.is_empty(), Some(groups))
+                    }
+                    _ => (false, None),
                 }
-                Ok(_) => {
-                    CH_R.signal(String::new());
+            };
+            if !due {
+                continue;
+            }
+            info!("cycle");
+            event::publish(Event::CycleStarted);
+            let mut watered = 0;
+            let in_cycle = |pos: &WateringPosition| {
+                groups
+                    .as_ref()
+                    .is_none_or(|groups| groups.contains(&pos.group))
+            };
+            // Edits made while watering take effect with the next cycle, so
+            // ids stay put and no position is skipped or watered twice.
+            let positions = farm.positions.clone();
+            let mut entry = history::Entry {
+                seq: 0,
+                date: Clock::today(&mut clock),
+                at: Clock::now(&mut clock),
+                uptime_s: Instant::now().as_secs() as u32,
+                planned: positions.iter().filter(|pos| in_cycle(pos)).count() as u8,
+                watered: 0,
+                pump_ms: 0,
+                fault: None,
+            };
+            for (id, pos) in positions.iter().enumerate() {
+                if !in_cycle(pos) {
+                    continue;
                 }
-                Err(_) => {}
-            }
-            for pos in positions.iter() {
-                match CH.try_receive() {
-                    Ok(Cmd::Stop) => {
-                        CH_R.signal(String::new());
-                        schedule_enabled = false;
-                        break;
-                    }
-                    Ok(_) => {
-                        CH_R.signal(String::new());
-                    }
-                    Err(_) => {}
+                farm.cycle_pos = Some(id);
+                if ESTOP.load(Ordering::Relaxed) {
+                    farm.enabled = false;
+                    break;
                 }
+                let axes = [&mut x, &mut y, &mut z];
+                if !farm_poll(&mut farm, &mut storage, &mut clock, axes, &mut pump).await {
+                    break;
+                }
+                // A stop on the way down skips the retract after watering,
+                // so make sure the nozzle is up before travelling.
+                z.goto(0).await;
                 join(x.goto(pos.x), y.goto(pos.y)).await;
                 z.goto(pos.z).await;
-                pump.on();
-                Timer::after(Duration::from_millis(pos.dur_ms.into())).await;
-                pump.off();
+                // Stopped on the way: no water, `farm_poll` takes the stop
+                // on the next round.
+                if HALT.load(Ordering::Relaxed) || ESTOP.load(Ordering::Relaxed) {
+                    continue;
+                }
+                event::publish(Event::PositionReached(id as u16));
+                watered += 1;
+                let started = Instant::now();
+                water(&mut pump, Duration::from_millis(pos.dur_ms.into())).await;
+                entry.pump_ms += started.elapsed().as_millis() as u32;
                 z.goto(0).await;
             }
+            farm.cycle_pos = None;
+            event::publish(Event::CycleFinished { watered });
+            entry.watered = watered as u8;
+            if ESTOP.load(Ordering::Relaxed) {
+                entry.fault = Some(Fault::EStop);
+            }
+            if farm.history.push(&mut storage, entry).await.is_err() {
+                info!("Save history Error");
+                event::publish(Event::StorageFailed);
+            }
         }
+        farm.next_repeat = None;
 
-        while !schedule_enabled {
-            let cmd = CH.receive().await;
-            match cmd {
-                Cmd::Goto(val) => {
-                    futures::future::join3(
-                        x.goto(val.x.unwrap_or(x.current_pos())),
-                        y.goto(val.y.unwrap_or(y.current_pos())),
-                        z.goto(val.z.unwrap_or(z.current_pos())),
-                    )
-                    .await;
-                }
-                Cmd::Move(val) => {
-                    futures::future::join3(
-                        x.r#move(val.x.unwrap_or(0)),
-                        y.r#move(val.y.unwrap_or(0)),
-                        z.r#move(val.z.unwrap_or(0)),
-                    )
-                    .await;
-                }
-                Cmd::SpeedMin(val) => {
-                    x.set_speed_min(val.x.unwrap_or(x.speed_min()));
-                    y.set_speed_min(val.y.unwrap_or(y.speed_min()));
-                    z.set_speed_min(val.z.unwrap_or(z.speed_min()));
-                }
-                Cmd::SpeedMax(val) => {
-                    x.set_speed_max(val.x.unwrap_or(x.speed_max()));
-                    y.set_speed_max(val.y.unwrap_or(y.speed_max()));
-                    z.set_speed_max(val.z.unwrap_or(z.speed_max()));
-                }
-                Cmd::SpeedAccel(val) => {
-                    x.set_speed_accel(val.x.unwrap_or(x.speed_accel()));
-                    y.set_speed_accel(val.y.unwrap_or(y.speed_accel()));
-                    z.set_speed_accel(val.z.unwrap_or(z.speed_accel()));
-                }
-                Cmd::StepPerMM(val) => {
-                    x.set_step_per_mm(val.x.unwrap_or(x.step_per_mm()));
-                    y.set_step_per_mm(val.y.unwrap_or(y.step_per_mm()));
-                    z.set_step_per_mm(val.z.unwrap_or(z.step_per_mm()));
-                }
-                Cmd::AddPos(val, dur) => {
-                    if let Some(x) = val.x {
-                        if let Some(y) = val.y {
-                            if let Some(z) = val.z {
-                                positions
-                                    .push(WateringPosition {
-                                        x,
-                                        y,
-                                        z,
-                                        dur_ms: dur.unwrap_or(default_dur_ms),
-                                    })
-                                    .ok();
-                                positions[..].sort_unstable();
-                                backup(&mut storage, &positions).await;
-                            }
-                        }
-                    }
-                }
-                Cmd::WaterDuration(id, dur) => {
-                    if let Some(id) = id {
-                        if let Some(ref mut pos) = positions.get_mut(id as usize) {
-                            pos.dur_ms = dur;
-                        }
-                    } else {
-                        for pos in positions.iter_mut() {
-                            pos.dur_ms = dur;
-                        }
-                    }
-                    backup(&mut storage, &positions).await;
-                }
-                Cmd::DelPos(id) => {
-                    if let Some(_pos) = positions.get(id as usize).cloned() {
-                        positions.remove(id as usize);
-                        backup(&mut storage, &positions).await;
-                    }
-                }
-                Cmd::RepeatDur(dur) => {
-                    repeat_duration = Duration::from_millis(dur.into());
-                }
-                Cmd::PumpOn => {
-                    pump.on();
+        while !farm.enabled {
+            let cmd = match select(pin!(CH.receive()), pin!(FAULT.wait())).await {
+                Either::Left((cmd, _)) => cmd,
+                Either::Right(_) => {
+                    latch_fault(&mut x, &mut y, &mut z, &mut pump);
+                    continue;
                 }
-                Cmd::PumpOff => {
-                    pump.off();
+            };
+            let axes = [&x, &y, &z];
+            let reply = match shared(cmd, &mut farm, &mut storage, &mut clock, axes, &pump).await {
+                Ok(reply) => reply,
+                Err(cmd) => {
+                    let axes = [&mut x, &mut y, &mut z];
+                    idle(cmd, &mut farm, &mut storage, axes, &mut pump).await
                 }
-                Cmd::ListPos => {
-                    let mut buf = String::<5000>::new();
-                    for (id, pos) in positions.iter().enumerate() {
-                        writeln!(
-                            &mut buf,
-                            "{:2}: ({:4}, {:4}, {:4}) {:5}ms",
-                            id, pos.x, pos.y, pos.z, pos.dur_ms
-                        )
-                        .ok();
-                    }
-                    CH_R.signal(buf);
-                }
-                Cmd::Start => {
-                    schedule_enabled = true;
-                }
-                Cmd::Stop => (),
-                Cmd::Home => {
-                    x.set_current_pos(0);
-                    y.set_current_pos(0);
-                    z.set_current_pos(0);
+            };
+            CH_R.signal(reply);
+        }
+    }
+}
+
+/// Commands only run while farming is off: moves, settings and faults.
+async fn idle(
+    cmd: Cmd,
+    farm: &mut Farm,
+    storage: &mut Storage<I2cBus>,
+    axes: [&mut Axis; 3],
+    pump: &mut Pump<Pin>,
+) -> Reply {
+    let [x, y, z] = axes;
+    match cmd {
+        Cmd::Goto(val) => {
+            check_ready(x, y, z, true)?;
+            check_travel(x, y, z, &val)?;
+            join3(
+                x.goto(val.x.unwrap_or(x.current_pos())),
+                y.goto(val.y.unwrap_or(y.current_pos())),
+                z.goto(val.z.unwrap_or(z.current_pos())),
+            )
+            .await;
+        }
+        Cmd::Linear(val) => {
+            check_ready(x, y, z, true)?;
+            check_travel(x, y, z, &val)?;
+            let target = [
+                val.x.unwrap_or(x.current_pos()),
+                val.y.unwrap_or(y.current_pos()),
+                val.z.unwrap_or(z.current_pos()),
+            ];
+            stepper::linear(x, y, z, target, None).await;
+        }
+        Cmd::Move(val) => {
+            let target = relative(x, y, z, &val)?;
+            check_ready(x, y, z, false)?;
+            check_travel(x, y, z, &target)?;
+            join3(
+                x.r#move(val.x.unwrap_or(0)),
+                y.r#move(val.y.unwrap_or(0)),
+                z.r#move(val.z.unwrap_or(0)),
+            )
+            .await;
+        }
+        Cmd::SpeedMin(val) => {
+            x.set_speed_min(val.x.unwrap_or(x.speed_min()));
+            y.set_speed_min(val.y.unwrap_or(y.speed_min()));
+            z.set_speed_min(val.z.unwrap_or(z.speed_min()));
+        }
+        Cmd::SpeedMax(val) => {
+            x.set_speed_max(val.x.unwrap_or(x.speed_max()));
+            y.set_speed_max(val.y.unwrap_or(y.speed_max()));
+            z.set_speed_max(val.z.unwrap_or(z.speed_max()));
+        }
+        Cmd::SpeedAccel(val) => {
+            x.set_speed_accel(val.x.unwrap_or(x.speed_accel()));
+            y.set_speed_accel(val.y.unwrap_or(y.speed_accel()));
+            z.set_speed_accel(val.z.unwrap_or(z.speed_accel()));
+        }
+        Cmd::SpeedJerk(val) => {
+            x.set_speed_jerk(val.x.unwrap_or(x.speed_jerk()));
+            y.set_speed_jerk(val.y.unwrap_or(y.speed_jerk()));
+            z.set_speed_jerk(val.z.unwrap_or(z.speed_jerk()));
+        }
+        Cmd::StepPerMM(val) => {
+            if [val.x, val.y, val.z].contains(&Some(0)) {
+                return Err(CmdError::new(
+                    ErrorCode::OutOfRange,
+                    "step_per_mm must be above 0",
+                ));
+            }
+            x.set_step_per_mm(val.x.unwrap_or(x.step_per_mm()));
+            y.set_step_per_mm(val.y.unwrap_or(y.step_per_mm()));
+            z.set_step_per_mm(val.z.unwrap_or(z.step_per_mm()));
+        }
+        Cmd::PumpOn => {
+            check_ready(x, y, z, false)?;
+            pump.on();
+        }
+        Cmd::PumpOff => {
+            pump.off();
+        }
+        Cmd::Start => {
+            check_ready(x, y, z, true)?;
+            farm.enabled = true;
+            save_schedule(storage, farm.enabled, farm.repeat_duration).map_err(not_saved)?;
+        }
+        Cmd::Stop => {
+            // Whatever was moving has halted by now.
+            HALT.store(false, Ordering::Relaxed);
+            pump.off();
+        }
+        Cmd::Home(axes) => {
+            check_ready(x, y, z, false)?;
+            home(x, y, z, axes).await.map_err(home_failed)?;
+        }
+        Cmd::HomeOffset(val) => {
+            x.set_home_offset(val.x.unwrap_or(x.home_offset()));
+            y.set_home_offset(val.y.unwrap_or(y.home_offset()));
+            z.set_home_offset(val.z.unwrap_or(z.home_offset()));
+        }
+        Cmd::HomeDir(val) => {
+            x.set_home_positive(val.x.map_or(x.home_positive(), |d| d > 0));
+            y.set_home_positive(val.y.map_or(y.home_positive(), |d| d > 0));
+            z.set_home_positive(val.z.map_or(z.home_positive(), |d| d > 0));
+        }
+        Cmd::HomeSpeed(val) => {
+            x.set_home_speed(val.x.unwrap_or(x.home_speed()));
+            y.set_home_speed(val.y.unwrap_or(y.home_speed()));
+            z.set_home_speed(val.z.unwrap_or(z.home_speed()));
+        }
+        Cmd::LimitMin(val) => {
+            check_limits(x, y, z, &val, &Set::default())?;
+            x.set_travel_min(val.x.unwrap_or(x.travel_min()));
+            y.set_travel_min(val.y.unwrap_or(y.travel_min()));
+            z.set_travel_min(val.z.unwrap_or(z.travel_min()));
+            save_limits(storage, x, y, z).map_err(not_saved)?;
+        }
+        Cmd::LimitMax(val) => {
+            check_limits(x, y, z, &Set::default(), &val)?;
+            x.set_travel_max(val.x.unwrap_or(x.travel_max()));
+            y.set_travel_max(val.y.unwrap_or(y.travel_max()));
+            z.set_travel_max(val.z.unwrap_or(z.travel_max()));
+            save_limits(storage, x, y, z).map_err(not_saved)?;
+        }
+        Cmd::SaveConfig => {
+            save_config(storage, x, y, z).await.map_err(not_saved)?;
+        }
+        Cmd::ResetConfig => {
+            for axis in [&mut *x, &mut *y, &mut *z] {
+                axis.set_config(AxisConfig::default());
+            }
+            save_config(storage, x, y, z).await.map_err(not_saved)?;
+        }
+        Cmd::Gcode(code) => return gcode(code, &mut farm.gcode, [x, y, z], pump).await,
+        Cmd::EStop => {
+            latch_fault(x, y, z, pump);
+            return text("E-stop latched, reset then home\n");
+        }
+        Cmd::Reset => {
+            if ESTOP_INPUT.load(Ordering::Relaxed) {
+                return Err(CmdError::new(ErrorCode::Fault, "e-stop input still open"));
+            }
+            ESTOP.store(false, Ordering::Relaxed);
+            HALT.store(false, Ordering::Relaxed);
+            return text("Reset, home before moving\n");
+        }
+        // Answered by `shared`.
+        _ => {}
+    }
+    Ok(Answer::default())
+}
+
+/// Commands that are safe while farming: queries, and edits of the position
+/// list and schedule table which the cycle picks up as it goes. Anything else
+/// is handed back to the caller.
+async fn shared(
+    cmd: Cmd,
+    farm: &mut Farm,
+    storage: &mut Storage<I2cBus>,
+    clock: &mut Rtc,
+    axes: [&Axis; 3],
+    pump: &Pump<Pin>,
+) -> Result<Reply, Cmd> {
+    let [x, y, z] = axes;
+    let reply = match cmd {
+        Cmd::AddPos(val, dur) => {
+            let (Some(px), Some(py), Some(pz)) = (val.x, val.y, val.z) else {
+                return Ok(Err(CmdError::new(
+                    ErrorCode::MissingAxis,
+                    "x, y and z required",
+                )));
+            };
+            if let Err(err) = check_travel(x, y, z, &val) {
+                return Ok(Err(err));
+            }
+            let pos = WateringPosition {
+                x: px,
+                y: py,
+                z: pz,
+                dur_ms: dur.unwrap_or(DEFAULT_DUR_MS),
+                group: 0,
+            };
+            if farm.positions.push(pos).is_err() {
+                return Ok(Err(CmdError::new(ErrorCode::Full, "position list full")));
+            }
+            farm.positions[..].sort_unstable();
+            save_positions(farm, storage).await
+        }
+        Cmd::WaterDuration(id, dur) => {
+            if let Some(id) = id {
+                let Some(pos) = farm.positions.get_mut(id as usize) else {
+                    return Ok(Err(out_of_range()));
+                };
+                pos.dur_ms = dur;
+            } else {
+                for pos in farm.positions.iter_mut() {
+                    pos.dur_ms = dur;
                 }
-                Cmd::Help => {
-                    let help = include_str!("./help.txt");
-                    CH_R.signal(String::try_from(help).unwrap());
+            }
+            save_positions(farm, storage).await
+        }
+        Cmd::DelPos(id) => {
+            if (id as usize) < farm.positions.len() {
+                farm.positions.remove(id as usize);
+                save_positions(farm, storage).await
+            } else {
+                Err(out_of_range())
+            }
+        }
+        Cmd::GroupSet(id, group) => {
+            if let Some(pos) = farm.positions.get_mut(id as usize) {
+                pos.group = group;
+                save_positions(farm, storage).await
+            } else {
+                Err(out_of_range())
+            }
+        }
+        Cmd::RepeatDur(dur) => {
+            farm.repeat_duration = Duration::from_millis(dur.into());
+            save_schedule(storage, farm.enabled, farm.repeat_duration)
+                .map(|_| Answer::default())
+                .map_err(not_saved)
+        }
+        Cmd::ListPos => Ok(Answer::Positions(farm.positions.clone())),
+        Cmd::LogShow(n) => farm
+            .history
+            .read(storage, n.unwrap_or(10) as usize)
+            .map(Answer::History)
+            .map_err(|_| CmdError::new(ErrorCode::Storage, "eeprom read failed")),
+        Cmd::LogClear => farm
+            .history
+            .clear(storage)
+            .await
+            .map(|_| Answer::default())
+            .map_err(not_saved),
+        Cmd::Status => {
+            let next = next_cycle(farm, clock);
+            let last_write = storage.last_write();
+            Ok(Answer::Text(status(
+                farm,
+                next,
+                [x, y, z],
+                pump.is_on(),
+                last_write,
+            )))
+        }
+        Cmd::Where => {
+            let mut buf = String::<5000>::new();
+            for (name, axis) in [("x", x), ("y", y), ("z", z)] {
+                writeln!(
+                    &mut buf,
+                    "{}: {:>8} mm {:>8} steps{}",
+                    name,
+                    Mm(axis.current_pos()),
+                    axis.step_pos(),
+                    if axis.homed() { "" } else { " (not homed)" }
+                )
+                .ok();
+            }
+            Ok(Answer::Text(buf))
+        }
+        Cmd::ListLimit => {
+            let mut buf = String::<5000>::new();
+            for (name, axis) in [("x", x), ("y", y), ("z", z)] {
+                writeln!(
+                    &mut buf,
+                    "{}: [{:>8}, {:>8}]",
+                    name,
+                    Mm(axis.travel_min()),
+                    Mm(axis.travel_max())
+                )
+                .ok();
+            }
+            Ok(Answer::Text(buf))
+        }
+        Cmd::TimeSet(time) => match Clock::set(clock, time) {
+            Ok(_) => {
+                farm.last_minute = None;
+                Ok(Answer::default())
+            }
+            Err(_) => Err(CmdError::new(ErrorCode::Clock, "set time failed")),
+        },
+        Cmd::TimeGet => match Clock::now(clock) {
+            Some(t) => {
+                let mut buf = String::<5000>::new();
+                writeln!(&mut buf, "{:02}:{:02}:{:02}", t.hour, t.minute, t.second).ok();
+                Ok(Answer::Text(buf))
+            }
+            None => Err(CmdError::new(ErrorCode::Clock, "clock not running")),
+        },
+        Cmd::DateSet(date) => match Clock::set_date(clock, date) {
+            Ok(_) => Ok(Answer::default()),
+            Err(_) => Err(CmdError::new(ErrorCode::Clock, "set date failed")),
+        },
+        Cmd::DateGet => match Clock::today(clock) {
+            Some(d) => {
+                let mut buf = String::<5000>::new();
+                writeln!(&mut buf, "{:04}-{:02}-{:02}", d.year, d.month, d.day).ok();
+                Ok(Answer::Text(buf))
+            }
+            None => Err(CmdError::new(ErrorCode::Clock, "clock not running")),
+        },
+        Cmd::ScheduleAdd(entry) => {
+            if farm.table.push(entry).is_err() {
+                Err(CmdError::new(ErrorCode::Full, "schedule table full"))
+            } else {
+                save_table(storage, &farm.table)
+                    .await
+                    .map(|_| Answer::default())
+                    .map_err(not_saved)
+            }
+        }
+        Cmd::ScheduleDel(id) => {
+            if (id as usize) < farm.table.len() {
+                farm.table.remove(id as usize);
+                save_table(storage, &farm.table)
+                    .await
+                    .map(|_| Answer::default())
+                    .map_err(not_saved)
+            } else {
+                Err(out_of_range())
+            }
+        }
+        Cmd::ScheduleList => {
+            let mut buf = String::<5000>::new();
+            for (id, e) in farm.table.iter().enumerate() {
+                let (h, m) = (e.start / 60, e.start % 60);
+                if e.every == 0 {
+                    writeln!(&mut buf, "{:2}: at {:02}:{:02} group {}", id, h, m, e.group).ok();
+                } else {
+                    writeln!(
+                        &mut buf,
+                        "{:2}: every {} min from {:02}:{:02} to {:02}:{:02} group {}",
+                        id,
+                        e.every,
+                        h,
+                        m,
+                        e.end / 60,
+                        e.end % 60,
+                        e.group
+                    )
+                    .ok();
                 }
             }
-            if CH_R.signaled() {
+            Ok(Answer::Text(buf))
+        }
+        Cmd::Help => text(include_str!("./help.txt")),
+        Cmd::Gcode(Gcode::Position) => {
+            let mut buf = String::<5000>::new();
+            writeln!(
+                &mut buf,
+                "X:{} Y:{} Z:{}",
+                Mm(x.current_pos()),
+                Mm(y.current_pos()),
+                Mm(z.current_pos())
+            )
+            .ok();
+            Ok(Answer::Text(buf))
+        }
+        cmd => return Err(cmd),
+    };
+    Ok(reply)
+}
+
+/// One line of G-code, on top of the same operations as the console
+/// commands. Moves run to completion before the reply, so `M400` has nothing
+/// left to wait for.
+async fn gcode(
+    code: Gcode,
+    modal: &mut Modal,
+    axes: [&mut Axis; 3],
+    pump: &mut Pump<Pin>,
+) -> Reply {
+    let [x, y, z] = axes;
+    match code {
+        Gcode::Move {
+            rapid,
+            target,
+            feed,
+        } => {
+            if feed.is_some() {
+                modal.feed = feed;
+            }
+            let target = if modal.relative {
+                relative(x, y, z, &target)?
+            } else {
+                target
+            };
+            check_ready(x, y, z, !modal.relative)?;
+            check_travel(x, y, z, &target)?;
+            let target = [
+                target.x.unwrap_or(x.current_pos()),
+                target.y.unwrap_or(y.current_pos()),
+                target.z.unwrap_or(z.current_pos()),
+            ];
+            if rapid {
+                join3(x.goto(target[0]), y.goto(target[1]), z.goto(target[2])).await;
             } else {
-                CH_R.signal(String::try_from("").unwrap());
+                let feed = modal.feed.map(|f| f as f32 / 60_000.0);
+                stepper::linear(x, y, z, target, feed).await;
+            }
+        }
+        Gcode::Home(axes) => {
+            check_ready(x, y, z, false)?;
+            home(x, y, z, axes).await.map_err(home_failed)?;
+        }
+        Gcode::Absolute => modal.relative = false,
+        Gcode::Relative => modal.relative = true,
+        Gcode::Dwell(ms) => dwell(Duration::from_millis(ms.into())).await,
+        Gcode::PumpOn => {
+            check_ready(x, y, z, false)?;
+            pump.on();
+        }
+        Gcode::PumpOff => pump.off(),
+        // `Position` is answered by `shared`.
+        Gcode::Position | Gcode::Wait => {}
+    }
+    Ok(Answer::default())
+}
+
+/// Write the position list back after an edit. The edit stays in effect
+/// either way, a failed write only means it is lost on reset.
+async fn save_positions(farm: &mut Farm, storage: &mut Storage<I2cBus>) -> Reply {
+    let result = backup(storage, &farm.positions).await;
+    farm.table_health = TableHealth::saved(result);
+    result.map(|_| Answer::default()).map_err(not_saved)
+}
+
+fn text(msg: &str) -> Reply {
+    String::try_from(msg)
+        .map(Answer::Text)
+        .map_err(|_| CmdError::new(ErrorCode::TooLong, "reply too long"))
+}
+
+fn home_failed(e: HomeError) -> CmdError {
+    let mut err = CmdError::new(ErrorCode::Home, "");
+    write!(&mut err.msg, "home failed: {:?}", e).ok();
+    err
+}
+
+fn out_of_range() -> CmdError {
+    CmdError::new(ErrorCode::OutOfRange, "index out of range")
+}
+
+fn not_saved(_: ()) -> CmdError {
+    event::publish(Event::StorageFailed);
+    CmdError::new(ErrorCode::Storage, "eeprom write failed, lost on reset")
+}
+
+/// Wait until `until`, answering commands meanwhile. Returns false once
+/// farming has to stop.
+async fn farm_wait(
+    until: Instant,
+    farm: &mut Farm,
+    storage: &mut Storage<I2cBus>,
+    clock: &mut Rtc,
+    axes: [&mut Axis; 3],
+    pump: &mut Pump<Pin>,
+) -> bool {
+    let [x, y, z] = axes;
+    loop {
+        let cmd = match select(
+            pin!(Timer::at(until)),
+            select(pin!(CH.receive()), pin!(FAULT.wait())),
+        )
+        .await
+        {
+            Either::Left(_) => return true,
+            Either::Right((Either::Left((cmd, _)), _)) => cmd,
+            Either::Right((Either::Right(_), _)) => {
+                latch_fault(x, y, z, pump);
+                farm.enabled = false;
+                return false;
+            }
+        };
+        if !while_farming(cmd, farm, storage, clock, [&mut *x, &mut *y, &mut *z], pump).await {
+            return false;
+        }
+    }
+}
+
+/// Answer the commands queued up while moving between positions. Returns
+/// false once farming has to stop.
+async fn farm_poll(
+    farm: &mut Farm,
+    storage: &mut Storage<I2cBus>,
+    clock: &mut Rtc,
+    axes: [&mut Axis; 3],
+    pump: &mut Pump<Pin>,
+) -> bool {
+    let [x, y, z] = axes;
+    while let Ok(cmd) = CH.try_receive() {
+        if !while_farming(cmd, farm, storage, clock, [&mut *x, &mut *y, &mut *z], pump).await {
+            return false;
+        }
+    }
+    true
+}
+
+/// One command during the schedule. Returns false once farming has to stop.
+async fn while_farming(
+    cmd: Cmd,
+    farm: &mut Farm,
+    storage: &mut Storage<I2cBus>,
+    clock: &mut Rtc,
+    axes: [&mut Axis; 3],
+    pump: &mut Pump<Pin>,
+) -> bool {
+    let [x, y, z] = axes;
+    let cmd = match shared(cmd, farm, storage, clock, [&*x, &*y, &*z], pump).await {
+        Ok(reply) => {
+            CH_R.signal(reply);
+            return true;
+        }
+        Err(cmd) => cmd,
+    };
+    let (reply, farming) = match cmd {
+        Cmd::Stop => {
+            HALT.store(false, Ordering::Relaxed);
+            farm.enabled = false;
+            let saved = save_schedule(storage, farm.enabled, farm.repeat_duration);
+            (saved.map(|_| Answer::default()).map_err(not_saved), false)
+        }
+        Cmd::EStop => {
+            latch_fault(x, y, z, pump);
+            farm.enabled = false;
+            (text("E-stop latched, reset then home\n"), false)
+        }
+        Cmd::Start => (text("Already farming\n"), true),
+        _ => (
+            Err(CmdError::new(ErrorCode::Busy, "stop farming first")),
+            true,
+        ),
+    };
+    CH_R.signal(reply);
+    farming
+}
+
+/// The pulses were cut without deceleration, so steps may have been lost.
+fn latch_fault(x: &mut Axis, y: &mut Axis, z: &mut Axis, pump: &mut Pump<Pin>) {
+    // Latching may come from polling ESTOP rather than waiting on FAULT;
+    // drop the pending signal so the idle loop doesn't latch a second time.
+    FAULT.reset();
+    pump.off();
+    x.invalidate_home();
+    y.invalidate_home();
+    z.invalidate_home();
+    info!("E-stop latched");
+    event::publish(Event::Fault(Fault::EStop));
+}
+
+/// Refuse to move while an E-stop is latched, or before homing if `need_home`.
+fn check_ready(x: &Axis, y: &Axis, z: &Axis, need_home: bool) -> Result<(), CmdError> {
+    if ESTOP.load(Ordering::Relaxed) {
+        Err(CmdError::new(
+            ErrorCode::Fault,
+            "e-stop latched, reset first",
+        ))
+    } else if need_home && !(x.homed() && y.homed() && z.homed()) {
+        Err(CmdError::new(
+            ErrorCode::NotHomed,
+            "position unknown, home first",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Run the pump for `dur`, switching it off as soon as a stop arrives.
+async fn water(pump: &mut Pump<Pin>, dur: Duration) {
+    pump.on();
+    dwell(dur).await;
+    pump.off();
+}
+
+/// Wait for `dur`, cut short by a stop.
+async fn dwell(dur: Duration) {
+    let mut left = dur;
+    while left > Duration::from_ticks(0)
+        && !HALT.load(Ordering::Relaxed)
+        && !ESTOP.load(Ordering::Relaxed)
+    {
+        let slice = left.min(Duration::from_millis(10));
+        Timer::after(slice).await;
+        left -= slice;
+    }
+}
+
+/// Time left until the schedule waters next: the repeat timer without a
+/// table, otherwise the next table entry as seen by the clock.
+fn next_cycle(farm: &Farm, clock: &mut Rtc) -> Option<Duration> {
+    if !farm.enabled {
+        return None;
+    } else if farm.table.is_empty() {
+        return farm
+            .next_repeat
+            .map(|at| at.saturating_duration_since(Instant::now()));
+    }
+    let now = Clock::now(clock)?;
+    let ahead = schedule::next_due(&farm.table, now.minute_of_day())?;
+    Some(Duration::from_secs(ahead as u64 * 60 - now.second as u64))
+}
+
+fn write_hms(buf: &mut String<5000>, secs: u64) {
+    write!(
+        buf,
+        "{}d {:02}:{:02}:{:02}",
+        secs / 86_400,
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
+    .ok();
+}
+
+fn status(
+    farm: &Farm,
+    next_cycle: Option<Duration>,
+    axes: [&Axis; 3],
+    pump_on: bool,
+    last_write: Option<Result<(), ()>>,
+) -> String<5000> {
+    let mut buf = String::new();
+    let schedule = match (farm.enabled, ESTOP.load(Ordering::Relaxed)) {
+        (_, true) => "stopped, e-stop latched",
+        (true, false) => "running",
+        (false, false) => "stopped",
+    };
+    writeln!(&mut buf, "schedule: {}", schedule).ok();
+    let count = farm.positions.len();
+    match farm.cycle_pos {
+        Some(id) => writeln!(
+            &mut buf,
+            "cycle: watering position {}, {} positions",
+            id, count
+        ),
+        None => writeln!(&mut buf, "cycle: idle, {} positions", count),
+    }
+    .ok();
+    write!(&mut buf, "next cycle: ").ok();
+    match next_cycle {
+        Some(left) => {
+            write!(&mut buf, "in ").ok();
+            write_hms(&mut buf, left.as_secs());
+            writeln!(&mut buf).ok();
+        }
+        None => {
+            writeln!(&mut buf, "none").ok();
+        }
+    }
+    writeln!(&mut buf, "pump: {}", if pump_on { "on" } else { "off" }).ok();
+    for (name, axis) in ["x", "y", "z"].into_iter().zip(axes) {
+        writeln!(
+            &mut buf,
+            "{}: {} mm{}, speed {}-{} mm/s, accel {} mm/s^2, jerk {} mm/s^3, {} step/mm",
+            name,
+            Mm(axis.current_pos()),
+            if axis.homed() { "" } else { " (not homed)" },
+            axis.speed_min(),
+            axis.speed_max(),
+            axis.speed_accel(),
+            axis.speed_jerk(),
+            axis.step_per_mm()
+        )
+        .ok();
+    }
+    let last_write = match last_write {
+        None => "none yet",
+        Some(Ok(())) => "ok",
+        Some(Err(())) => "failed",
+    };
+    writeln!(
+        &mut buf,
+        "eeprom: position table {}, last write {}",
+        farm.table_health.as_str(),
+        last_write
+    )
+    .ok();
+    write!(&mut buf, "uptime: ").ok();
+    write_hms(&mut buf, Instant::now().as_secs());
+    writeln!(&mut buf).ok();
+    buf
+}
+
+/// Targets `by` away from the current position.
+fn relative(x: &Axis, y: &Axis, z: &Axis, by: &Set) -> Result<Set, CmdError> {
+    let add = |axis: &Axis, d: Option<i32>| match d {
+        Some(d) => axis
+            .current_pos()
+            .checked_add(d)
+            .map(Some)
+            .ok_or_else(|| CmdError::new(ErrorCode::Travel, "distance out of range")),
+        None => Ok(None),
+    };
+    Ok(Set {
+        x: add(x, by.x)?,
+        y: add(y, by.y)?,
+        z: add(z, by.z)?,
+    })
+}
+
+/// Refuse travel limits that would put an axis' minimum above its maximum,
+/// `restore_limits` would throw the whole record away at the next boot.
+fn check_limits(x: &Axis, y: &Axis, z: &Axis, min: &Set, max: &Set) -> Result<(), CmdError> {
+    for (name, axis, min, max) in [
+        ("x", x, min.x, max.x),
+        ("y", y, min.y, max.y),
+        ("z", z, min.z, max.z),
+    ] {
+        let min = min.unwrap_or(axis.travel_min());
+        let max = max.unwrap_or(axis.travel_max());
+        if min > max {
+            let mut err = CmdError::new(ErrorCode::Travel, "");
+            write!(
+                &mut err.msg,
+                "{} min {} above max {}",
+                name,
+                Mm(min),
+                Mm(max)
+            )
+            .ok();
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// Refuse targets outside the soft travel limits, naming the first offending
+/// axis in the reply.
+fn check_travel(x: &Axis, y: &Axis, z: &Axis, target: &Set) -> Result<(), CmdError> {
+    for (name, axis, pos) in [("x", x, target.x), ("y", y, target.y), ("z", z, target.z)] {
+        if let Some(pos) = pos {
+            if !axis.in_travel(pos) {
+                let mut err = CmdError::new(ErrorCode::Travel, "");
+                write!(
+                    &mut err.msg,
+                    "{} {} out of range [{}, {}]",
+                    name,
+                    Mm(pos),
+                    Mm(axis.travel_min()),
+                    Mm(axis.travel_max())
+                )
+                .ok();
+                return Err(err);
             }
         }
     }
+    Ok(())
 }
 
-async fn restore(sto: &mut Storage) -> Result<Vec<WateringPosition, 100>, ()> {
-    let mut size = 0;
+/// Home the selected axes together. Z is homed first on its own so the
+/// nozzle is clear of the trays before X and Y travel.
+async fn home(x: &mut Axis, y: &mut Axis, z: &mut Axis, axes: Axes) -> Result<(), HomeError> {
+    let result = home_axes(x, y, z, axes).await;
+    event::publish(match result {
+        Ok(()) => Event::Homed(axes),
+        Err(_) => Event::Fault(Fault::Home),
+    });
+    result
+}
+
+async fn home_axes(x: &mut Axis, y: &mut Axis, z: &mut Axis, axes: Axes) -> Result<(), HomeError> {
+    if axes.z {
+        z.home().await?;
+    }
+    let (rx, ry) = join(
+        async {
+            if axes.x {
+                x.home().await
+            } else {
+                Ok(())
+            }
+        },
+        async {
+            if axes.y {
+                y.home().await
+            } else {
+                Ok(())
+            }
+        },
+    )
+    .await;
+    rx.and(ry)
+}
+
+/// Read the position table, falling back to the headerless layout written by
+/// older firmware and rewriting it in the current one.
+async fn restore<I: I2cWrite + WriteRead>(
+    sto: &mut Storage<I>,
+) -> Result<Vec<WateringPosition, MAX_POSITIONS>, ()> {
     Timer::after(Duration::from_millis(100)).await;
-    if let Ok(page) = sto.read_page(0) {
-        size = page[0];
+    let page = sto.read_page(0)?;
+
+    if page[..4] != TABLE_MAGIC {
+        let list = restore_legacy(sto, page[0])?;
+        info!("migrating {} positions", list.len());
+        if backup(sto, &list).await.is_err() {
+            info!("Migration Error");
+        }
+        return Ok(list);
     }
+    // A torn header is not a legacy table, migrating would read its magic as
+    // a record count and overwrite the records.
+    let Some(header) = TableHeader::from_page(&page) else {
+        info!("position table header crc mismatch");
+        return Err(());
+    };
 
-    let list = (1..=size)
-        .filter_map(|idx| sto.read_page(idx).ok())
-        .filter_map(|page| postcard::from_bytes::<WateringPosition>(&page).ok())
-        .collect::<Vec<WateringPosition, 100>>();
+    if header.count as usize > MAX_POSITIONS {
+        return Err(());
+    }
+    let mut crc = CRC_INIT;
+    let mut list = Vec::new();
+    for idx in 1..=header.count {
+        let page = sto.read_page(idx)?;
+        crc = crc16(crc, &page);
+        let pos = decode_position(header.version, &page).ok_or(())?;
+        list.push(pos).map_err(|_| ())?;
+    }
+    if crc != header.crc {
+        info!("position table crc mismatch");
+        return Err(());
+    }
     Ok(list)
 }
 
-async fn backup(sto: &mut Storage, list: &Vec<WateringPosition, 100>) -> Result<(), ()> {
-    let mut buf = [0; 32];
-    buf[0] = list.len() as u8;
-    sto.write_page(0, buf)?;
+/// Version 0: a bare record count in page 0 followed by one record per page.
+fn restore_legacy<I: I2cWrite + WriteRead>(
+    sto: &mut Storage<I>,
+    count: u8,
+) -> Result<Vec<WateringPosition, MAX_POSITIONS>, ()> {
+    if count as usize > MAX_POSITIONS {
+        return Err(());
+    }
+    let mut list = Vec::new();
+    for idx in 1..=count {
+        let page = sto.read_page(idx)?;
+        let pos = decode_position(0, &page).ok_or(())?;
+        list.push(pos).map_err(|_| ())?;
+    }
+    Ok(list)
+}
+
+/// Decode one record written with table layout `version`.
+fn decode_position(version: u8, page: &[u8]) -> Option<WateringPosition> {
+    match version {
+        0 | 1 => postcard::from_bytes::<WateringPositionV1>(page)
+            .ok()
+            .map(Into::into),
+        2 => postcard::from_bytes::<WateringPositionV2>(page)
+            .ok()
+            .map(Into::into),
+        3 => postcard::from_bytes(page).ok(),
+        _ => None,
+    }
+}
 
+/// Records go first and the header last, so a write cut short leaves a
+/// header whose crc no longer matches.
+async fn backup<I: I2cWrite + WriteRead>(
+    sto: &mut Storage<I>,
+    list: &Vec<WateringPosition, MAX_POSITIONS>,
+) -> Result<(), ()> {
+    let mut crc = CRC_INIT;
     for (idx, p) in list.iter().enumerate() {
+        let mut buf = [0; 32];
+        postcard::to_slice(p, &mut buf).map_err(|_| ())?;
+        crc = crc16(crc, &buf);
+        sto.write_page(1 + idx as u8, buf)?;
         Timer::after(Duration::from_millis(10)).await;
+    }
+
+    let header = TableHeader {
+        version: TABLE_VERSION,
+        count: list.len() as u8,
+        crc,
+    };
+    sto.write_page(0, header.to_page())
+}
+
+fn save_limits<I: I2cWrite + WriteRead>(
+    sto: &mut Storage<I>,
+    x: &Axis,
+    y: &Axis,
+    z: &Axis,
+) -> Result<(), ()> {
+    let limits = Limits {
+        min: [x.travel_min(), y.travel_min(), z.travel_min()],
+        max: [x.travel_max(), y.travel_max(), z.travel_max()],
+        version: LIMITS_VERSION,
+    };
+    let mut buf = [0; 32];
+    postcard::to_slice(&limits, &mut buf).map_err(|_| ())?;
+    sto.write_page(LIMITS_PAGE, buf)
+}
+
+fn restore_limits<I: I2cWrite + WriteRead>(
+    sto: &mut Storage<I>,
+    x: &mut Axis,
+    y: &mut Axis,
+    z: &mut Axis,
+) -> Result<(), ()> {
+    let page = sto.read_page(LIMITS_PAGE)?;
+    let mut limits = postcard::from_bytes::<Limits>(&page).map_err(|_| ())?;
+    match limits.version {
+        0 => {
+            for v in limits.min.iter_mut().chain(limits.max.iter_mut()) {
+                *v = v.saturating_mul(1000);
+            }
+        }
+        LIMITS_VERSION => (),
+        _ => return Err(()),
+    }
+    if (0..3).any(|i| limits.min[i] > limits.max[i]) {
+        return Err(());
+    }
+    for (i, axis) in [x, y, z].into_iter().enumerate() {
+        axis.set_travel_min(limits.min[i]);
+        axis.set_travel_max(limits.max[i]);
+    }
+    Ok(())
+}
+
+async fn save_config<I: I2cWrite + WriteRead>(
+    sto: &mut Storage<I>,
+    x: &Axis,
+    y: &Axis,
+    z: &Axis,
+) -> Result<(), ()> {
+    for (idx, axis) in [x, y, z].into_iter().enumerate() {
+        let record = ConfigRecord {
+            version: CONFIG_VERSION,
+            axis: axis.config(),
+        };
         let mut buf = [0; 32];
-        postcard::to_slice(p, &mut buf).ok();
-        sto.write_page(1 + idx as u8, buf).ok();
+        postcard::to_slice(&record, &mut buf).map_err(|_| ())?;
+        sto.write_page(CONFIG_PAGE + idx as u8, buf)?;
+        Timer::after(Duration::from_millis(10)).await;
     }
     Ok(())
 }
+
+fn restore_config<I: I2cWrite + WriteRead>(
+    sto: &mut Storage<I>,
+    x: &mut Axis,
+    y: &mut Axis,
+    z: &mut Axis,
+) -> Result<(), ()> {
+    let mut configs = [AxisConfig::default(); 3];
+    for (idx, config) in configs.iter_mut().enumerate() {
+        let page = sto.read_page(CONFIG_PAGE + idx as u8)?;
+        let axis = match page[0] {
+            1 => postcard::from_bytes::<ConfigRecordV1>(&page)
+                .map_err(|_| ())?
+                .axis
+                .into(),
+            CONFIG_VERSION => {
+                postcard::from_bytes::<ConfigRecord>(&page)
+                    .map_err(|_| ())?
+                    .axis
+            }
+            _ => return Err(()),
+        };
+        if axis.step_per_mm == 0 {
+            return Err(());
+        }
+        *config = axis;
+    }
+    for (axis, config) in [x, y, z].into_iter().zip(configs) {
+        axis.set_config(config);
+    }
+    Ok(())
+}
+
+fn save_schedule<I: I2cWrite + WriteRead>(
+    sto: &mut Storage<I>,
+    enabled: bool,
+    repeat: Duration,
+) -> Result<(), ()> {
+    let record = ScheduleRecord {
+        version: SCHEDULE_VERSION,
+        enabled,
+        repeat_ms: repeat.as_millis().try_into().unwrap_or(u32::MAX),
+    };
+    let mut buf = [0; 32];
+    postcard::to_slice(&record, &mut buf).map_err(|_| ())?;
+    sto.write_page(SCHEDULE_PAGE, buf)
+}
+
+fn restore_schedule<I: I2cWrite + WriteRead>(sto: &mut Storage<I>) -> Result<ScheduleRecord, ()> {
+    let page = sto.read_page(SCHEDULE_PAGE)?;
+    let record = postcard::from_bytes::<ScheduleRecord>(&page).map_err(|_| ())?;
+    if record.version != SCHEDULE_VERSION {
+        return Err(());
+    }
+    Ok(record)
+}
+
+async fn save_table<I: I2cWrite + WriteRead>(
+    sto: &mut Storage<I>,
+    table: &Table,
+) -> Result<(), ()> {
+    let record = TableRecord {
+        version: TABLE_RECORD_VERSION,
+        entries: table.clone(),
+    };
+    sto.write_record(TABLE_PAGE, &record).await
+}
+
+fn restore_table<I: I2cWrite + WriteRead>(sto: &mut Storage<I>) -> Result<Table, ()> {
+    match sto.read_record::<TableRecord>(TABLE_PAGE, TABLE_PAGES) {
+        Ok(record) if record.version == TABLE_RECORD_VERSION => Ok(record.entries),
+        _ => {
+            let record = sto.read_record::<TableRecordV1>(TABLE_PAGE, TABLE_PAGES)?;
+            if record.version != 1 {
+                return Err(());
+            }
+            Ok(record.entries.into_iter().map(Into::into).collect())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{block_on, SimEeprom};
+
+    fn pos(x: i32, dur_ms: u32) -> WateringPosition {
+        WateringPosition {
+            x,
+            y: 2000,
+            z: 0,
+            dur_ms,
+            group: 0,
+        }
+    }
+
+    #[test]
+    fn blank_chip_has_no_table() {
+        let mut sto = Storage::new(SimEeprom::new());
+        assert_eq!(block_on(restore(&mut sto)), Err(()));
+        // Nothing was mistaken for a legacy table and migrated.
+        assert_eq!(sto.read_page(0), Ok([0xFF; 32]));
+    }
+
+    #[test]
+    fn legacy_table_is_migrated() {
+        let mut sto = Storage::new(SimEeprom::new());
+        let mut page = [0; 32];
+        page[0] = 2;
+        sto.write_page(0, page).unwrap();
+        for (idx, x) in [(1, 10), (2, 20)] {
+            let old = WateringPositionV1 {
+                x,
+                y: 2,
+                z: 0,
+                dur_ms: 500,
+            };
+            let mut page = [0; 32];
+            postcard::to_slice(&old, &mut page).unwrap();
+            sto.write_page(idx, page).unwrap();
+        }
+
+        let expected = [pos(10_000, 500), pos(20_000, 500)];
+        assert_eq!(block_on(restore(&mut sto)).unwrap(), expected);
+        let header = TableHeader::from_page(&sto.read_page(0).unwrap()).unwrap();
+        assert_eq!((header.version, header.count), (TABLE_VERSION, 2));
+        assert_eq!(block_on(restore(&mut sto)).unwrap(), expected);
+    }
+
+    #[test]
+    fn corrupted_header_is_not_migrated() {
+        let mut sto = Storage::new(SimEeprom::new());
+        // As many records as the magic's first byte reads as a legacy count,
+        // so a migration would find every one of them.
+        let list: Vec<_, MAX_POSITIONS> = (0..TABLE_MAGIC[0] as i32)
+            .map(|i| pos(i * 1000, 100))
+            .collect();
+        block_on(backup(&mut sto, &list)).unwrap();
+        assert_eq!(block_on(restore(&mut sto)).unwrap(), list);
+
+        let mut page = sto.read_page(0).unwrap();
+        page[5] -= 1;
+        sto.write_page(0, page).unwrap();
+        assert_eq!(block_on(restore(&mut sto)), Err(()));
+        assert_eq!(sto.read_page(0), Ok(page));
+    }
+}
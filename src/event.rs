@@ -0,0 +1,83 @@
+//! Notifications pushed to the host without being asked for.
+//!
+//! The controller and the pump publish, every subscribed link gets a copy.
+//! Publishing never waits: a subscriber that falls behind by more than
+//! `QUEUE_LEN` events loses the oldest ones.
+
+use crate::command::Axes;
+use core::fmt;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex as Raw;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber};
+use serde::{Deserialize, Serialize};
+
+const QUEUE_LEN: usize = 16;
+/// The USB serial port, and the console of the host simulation.
+const SUBSCRIBERS: usize = 2;
+
+static EVENTS: PubSubChannel<Raw, Event, QUEUE_LEN, SUBSCRIBERS, 0> = PubSubChannel::new();
+
+pub type Events = Subscriber<'static, Raw, Event, QUEUE_LEN, SUBSCRIBERS, 0>;
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    CycleStarted,
+    /// Number of positions watered, fewer than planned if the cycle was cut
+    /// short.
+    CycleFinished {
+        watered: u16,
+    },
+    /// Id of the position the nozzle arrived at.
+    PositionReached(u16),
+    PumpOn,
+    /// How long the pump ran.
+    PumpOff {
+        ms: u32,
+    },
+    /// An EEPROM write failed, the change is lost on reset.
+    StorageFailed,
+    Homed(Axes),
+    Fault(Fault),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// E-stop latched, by command or by the input.
+    EStop,
+    /// Homing did not find or leave an endstop.
+    Home,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::CycleStarted => write!(f, "cycle started"),
+            Event::CycleFinished { watered } => {
+                write!(f, "cycle finished, {} positions watered", watered)
+            }
+            Event::PositionReached(id) => write!(f, "position {} reached", id),
+            Event::PumpOn => write!(f, "pump on"),
+            Event::PumpOff { ms } => write!(f, "pump off after {}ms", ms),
+            Event::StorageFailed => write!(f, "eeprom write failed"),
+            Event::Homed(axes) => {
+                write!(f, "homed")?;
+                for (name, homed) in [("x", axes.x), ("y", axes.y), ("z", axes.z)] {
+                    if homed {
+                        write!(f, " {}", name)?;
+                    }
+                }
+                Ok(())
+            }
+            Event::Fault(Fault::EStop) => write!(f, "fault: e-stop latched"),
+            Event::Fault(Fault::Home) => write!(f, "fault: home failed"),
+        }
+    }
+}
+
+pub fn publish(event: Event) {
+    EVENTS.immediate_publisher().publish_immediate(event);
+}
+
+/// `None` once every subscriber slot is taken.
+pub fn subscribe() -> Option<Events> {
+    EVENTS.subscriber().ok()
+}
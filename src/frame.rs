@@ -0,0 +1,236 @@
+//! Binary protocol for supervisor software, next to the text console.
+//!
+//! The host switches the USB serial port over by sending `HANDSHAKE` and gets
+//! a `Pong` frame back. From then on every message is a postcard encoded
+//! `Request` or `Response` followed by the little endian CRC-16 of those
+//! bytes, COBS encoded and terminated by a zero byte. `Op::Console` switches
+//! back to the line editor.
+
+use crate::command::Cmd;
+use crate::controller::{Answer, Reply, WateringPosition};
+use crate::event::Event;
+use crate::history::Entry;
+use crate::storage::{crc16, CRC_INIT};
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+/// Sent instead of a text line to enter binary mode.
+pub const HANDSHAKE: u8 = 0x02;
+/// Bumped whenever `Request` or `Response` change shape.
+pub const VERSION: u8 = 4;
+/// Longest request frame accepted, commands are a few dozen bytes.
+const MAX_REQUEST: usize = 128;
+/// Longest response frame: a full text reply plus id, CRC and COBS overhead.
+const MAX_RESPONSE: usize = 5100;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    /// Echoed in the response so the host can match them up.
+    pub id: u16,
+    pub op: Op,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Cmd(Cmd),
+    /// Answered with `Body::Pong`, to probe the link.
+    Ping,
+    /// Answered with `Body::Ok`, then back to the text console.
+    Console,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Response<'a> {
+    pub id: u16,
+    pub body: Body<'a>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum Body<'a> {
+    /// Reply text of the command, often empty.
+    Ok(&'a str),
+    /// Answer to `Cmd::ListPos`, coordinates in micrometers.
+    Positions(&'a [WateringPosition]),
+    /// Answer to `Cmd::LogShow`, newest cycle first.
+    History(&'a [Entry]),
+    /// Code and message as in `[ERR <code> <message>]`.
+    Err {
+        code: u8,
+        msg: &'a str,
+    },
+    Pong {
+        version: u8,
+    },
+    /// Pushed after `Cmd::Subscribe`, always with id 0.
+    Event(&'a Event),
+    /// CRC mismatch, undecodable or oversized request. The id is 0 as the
+    /// request's own could not be trusted.
+    BadFrame,
+}
+
+impl<'a> Body<'a> {
+    pub fn reply(reply: &'a Reply) -> Self {
+        match reply {
+            Ok(Answer::Text(text)) => Body::Ok(text),
+            Ok(Answer::Positions(list)) => Body::Positions(list),
+            Ok(Answer::History(list)) => Body::History(list),
+            Err(err) => Body::Err {
+                code: err.code as u8,
+                msg: &err.msg,
+            },
+        }
+    }
+}
+
+/// Frame buffers of one serial port.
+pub struct Framer {
+    rx: Vec<u8, MAX_REQUEST>,
+    /// The current frame outgrew `rx`, skip to its terminator.
+    overflow: bool,
+    raw: [u8; MAX_RESPONSE],
+    tx: [u8; MAX_RESPONSE],
+}
+
+impl Framer {
+    pub const fn new() -> Self {
+        Self {
+            rx: Vec::new(),
+            overflow: false,
+            raw: [0; MAX_RESPONSE],
+            tx: [0; MAX_RESPONSE],
+        }
+    }
+
+    /// Drop a partly received frame, on entering binary mode.
+    pub fn clear(&mut self) {
+        self.rx.clear();
+        self.overflow = false;
+    }
+
+    /// Feed one received byte. Returns the request once its terminator
+    /// arrives, or `Err` if the frame was bad.
+    pub fn push(&mut self, b: u8) -> Option<Result<Request, ()>> {
+        if b != 0 {
+            if self.rx.push(b).is_err() {
+                self.overflow = true;
+            }
+            return None;
+        }
+        let request = if self.overflow {
+            Err(())
+        } else {
+            decode(&mut self.rx)
+        };
+        self.clear();
+        Some(request)
+    }
+
+    /// Frame `response` for the wire, terminator included.
+    pub fn encode(&mut self, response: &Response) -> &[u8] {
+        // Replies are at most 5000 bytes, they always fit.
+        let len = postcard::to_slice(response, &mut self.raw[..MAX_RESPONSE - 2])
+            .map_or(0, |msg| msg.len());
+        let crc = crc16(CRC_INIT, &self.raw[..len]);
+        self.raw[len..len + 2].copy_from_slice(&crc.to_le_bytes());
+        let n = cobs::encode(&self.raw[..len + 2], &mut self.tx);
+        self.tx[n] = 0;
+        &self.tx[..=n]
+    }
+}
+
+fn decode(frame: &mut [u8]) -> Result<Request, ()> {
+    let n = cobs::decode_in_place(frame).map_err(|_| ())?;
+    if n < 2 {
+        return Err(());
+    }
+    let (msg, crc) = frame[..n].split_at(n - 2);
+    if crc16(CRC_INIT, msg) != u16::from_le_bytes([crc[0], crc[1]]) {
+        return Err(());
+    }
+    postcard::from_bytes(msg).map_err(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Set;
+
+    /// `request` as the host sends it, with `flip` xored into the CRC.
+    fn wire(request: &Request, flip: u16) -> Vec<u8, MAX_REQUEST> {
+        let mut raw = [0; MAX_REQUEST];
+        let len = postcard::to_slice(request, &mut raw).unwrap().len();
+        let crc = crc16(CRC_INIT, &raw[..len]) ^ flip;
+        raw[len..len + 2].copy_from_slice(&crc.to_le_bytes());
+        let mut out = [0; MAX_REQUEST];
+        let n = cobs::encode(&raw[..len + 2], &mut out);
+        let mut frame = Vec::from_slice(&out[..n]).unwrap();
+        frame.push(0).unwrap();
+        frame
+    }
+
+    fn feed(framer: &mut Framer, bytes: &[u8]) -> Option<Result<Request, ()>> {
+        let (last, rest) = bytes.split_last().unwrap();
+        for b in rest {
+            assert_eq!(framer.push(*b), None);
+        }
+        framer.push(*last)
+    }
+
+    fn goto() -> Request {
+        Request {
+            id: 7,
+            op: Op::Cmd(Cmd::Goto(Set {
+                x: Some(12_500),
+                ..Set::default()
+            })),
+        }
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let mut framer = Framer::new();
+        assert_eq!(feed(&mut framer, &wire(&goto(), 0)), Some(Ok(goto())));
+        let ping = Request {
+            id: 8,
+            op: Op::Ping,
+        };
+        assert_eq!(feed(&mut framer, &wire(&ping, 0)), Some(Ok(ping)));
+    }
+
+    #[test]
+    fn crc_mismatch_is_a_bad_frame() {
+        let mut framer = Framer::new();
+        assert_eq!(feed(&mut framer, &wire(&goto(), 1)), Some(Err(())));
+        assert_eq!(feed(&mut framer, &wire(&goto(), 0)), Some(Ok(goto())));
+    }
+
+    #[test]
+    fn oversized_frame_is_skipped_to_its_end() {
+        let mut framer = Framer::new();
+        for _ in 0..MAX_REQUEST + 10 {
+            assert_eq!(framer.push(0x55), None);
+        }
+        assert_eq!(framer.push(0), Some(Err(())));
+        assert_eq!(feed(&mut framer, &wire(&goto(), 0)), Some(Ok(goto())));
+    }
+
+    #[test]
+    fn pong_is_framed_with_crc() {
+        let mut framer = Framer::new();
+        let response = Response {
+            id: 3,
+            body: Body::Pong { version: VERSION },
+        };
+        let frame = framer.encode(&response);
+        let (end, body) = frame.split_last().unwrap();
+        assert_eq!(*end, 0);
+        assert!(!body.contains(&0));
+
+        let mut raw: Vec<u8, 16> = Vec::from_slice(body).unwrap();
+        let n = cobs::decode_in_place(&mut raw).unwrap();
+        // Id, `Body::Pong` variant and version, then the CRC.
+        assert_eq!(&raw[..n - 2], &[3, 4, VERSION]);
+        let crc = crc16(CRC_INIT, &raw[..n - 2]);
+        assert_eq!(&raw[n - 2..n], &crc.to_le_bytes());
+    }
+}
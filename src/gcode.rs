@@ -0,0 +1,226 @@
+//! G-code front end, for programs written for other CNC machines.
+//!
+//! Only one G or M code per line is understood, the subset an arm that
+//! waters plants can act on. Comments in `;` or parentheses, line numbers and
+//! `*` checksums are accepted and ignored. The controller keeps the modal
+//! state, see `Modal`.
+
+use crate::command::{parse_mm, Axes, Set};
+use heapless::Vec;
+use nom::{
+    branch::alt,
+    bytes::complete::{is_not, tag},
+    character::complete::{digit1, multispace0, satisfy},
+    combinator::{all_consuming, map_res, opt, rest, value},
+    multi::fold_many0,
+    sequence::{delimited, pair, preceded, terminated},
+    IResult, Parser,
+};
+use serde::{Deserialize, Serialize};
+
+/// Words per line, `G1 X Y Z F` plus a line number leaves some slack.
+const MAX_WORDS: usize = 8;
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub enum Gcode {
+    /// `G0` moves each axis on its own at full speed, `G1` moves in a
+    /// straight line. Coordinates in micrometers, relative after `G91`.
+    Move {
+        rapid: bool,
+        target: Set,
+        /// Feed rate in micrometers per minute, `F` is typed in mm/min.
+        feed: Option<u32>,
+    },
+    /// `G28`, the axes given or all of them.
+    Home(Axes),
+    /// `G90`
+    Absolute,
+    /// `G91`
+    Relative,
+    /// `G4 P<ms>` or `G4 S<s>`, in milliseconds.
+    Dwell(u32),
+    /// `M3` or `M106`
+    PumpOn,
+    /// `M5`, `M107` or `M106 S0`
+    PumpOff,
+    /// `M114`
+    Position,
+    /// `M400`
+    Wait,
+}
+
+/// Modal state carried from one line to the next.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Modal {
+    /// Coordinates of `G0` and `G1` are offsets from the current position.
+    pub relative: bool,
+    /// Last `F` seen, in micrometers per minute.
+    pub feed: Option<u32>,
+}
+
+/// Letter and value of one word, the value in thousandths like `parse_mm`.
+fn word(input: &str) -> IResult<&str, (char, Option<i32>)> {
+    preceded(
+        multispace0,
+        pair(
+            satisfy(|c| c.is_ascii_alphabetic()).map(|c| c.to_ascii_uppercase()),
+            opt(parse_mm),
+        ),
+    )
+    .parse(input)
+}
+
+/// `; to the end of the line`, `(inline)` or a `*71` checksum.
+fn skip(input: &str) -> IResult<&str, ()> {
+    preceded(
+        multispace0,
+        alt((
+            value((), preceded(tag(";"), rest)),
+            value((), delimited(tag("("), is_not(")"), tag(")"))),
+            value((), preceded(tag("*"), digit1)),
+        )),
+    )
+    .parse(input)
+}
+
+pub fn parse_gcode(input: &str) -> IResult<&str, Gcode> {
+    all_consuming(terminated(
+        map_res(
+            fold_many0(
+                alt((word.map(Some), skip.map(|_| None))),
+                || Some(Vec::<(char, Option<i32>), MAX_WORDS>::new()),
+                |words, w| match (words, w) {
+                    (Some(mut words), Some(w)) => words.push(w).ok().map(|_| words),
+                    (words, _) => words,
+                },
+            ),
+            |words| words.ok_or(()).and_then(|words| block(&words)),
+        ),
+        multispace0,
+    ))
+    .parse(input)
+}
+
+/// Interpret the words of one line.
+fn block(words: &[(char, Option<i32>)]) -> Result<Gcode, ()> {
+    let given = |letter: char| words.iter().any(|(l, _)| *l == letter);
+    let arg = |letter: char| {
+        words
+            .iter()
+            .find(|(l, _)| *l == letter)
+            .and_then(|(_, value)| *value)
+    };
+    let (letter, code) = words
+        .iter()
+        .copied()
+        .find(|(l, _)| *l == 'G' || *l == 'M')
+        .ok_or(())?;
+    let code = code.ok_or(())?;
+    if code % 1000 != 0 {
+        return Err(());
+    }
+    // Only the axes of `G28` go without a value, as in `G28 X Y`.
+    let home = (letter, code) == ('G', 28_000);
+    if words
+        .iter()
+        .any(|(l, value)| value.is_none() && !(home && matches!(l, 'X' | 'Y' | 'Z')))
+    {
+        return Err(());
+    }
+    let gcode = match (letter, code / 1000) {
+        ('G', code @ (0 | 1)) => Gcode::Move {
+            rapid: code == 0,
+            target: Set {
+                x: arg('X'),
+                y: arg('Y'),
+                z: arg('Z'),
+            },
+            feed: match arg('F') {
+                Some(f) => Some(u32::try_from(f).map_err(|_| ())?),
+                None => None,
+            },
+        },
+        ('G', 4) => {
+            // `P` is milliseconds, `S` seconds, the thousandths of either
+            // come out as milliseconds too.
+            let ms = match (arg('P'), arg('S')) {
+                (Some(p), _) => p / 1000,
+                (None, Some(s)) => s,
+                (None, None) => 0,
+            };
+            Gcode::Dwell(u32::try_from(ms).map_err(|_| ())?)
+        }
+        ('G', 28) => {
+            let axes = Axes {
+                x: given('X'),
+                y: given('Y'),
+                z: given('Z'),
+            };
+            if axes == Axes::default() {
+                Gcode::Home(Axes {
+                    x: true,
+                    y: true,
+                    z: true,
+                })
+            } else {
+                Gcode::Home(axes)
+            }
+        }
+        ('G', 90) => Gcode::Absolute,
+        ('G', 91) => Gcode::Relative,
+        ('M', 106) if arg('S') == Some(0) => Gcode::PumpOff,
+        ('M', 3 | 106) => Gcode::PumpOn,
+        ('M', 5 | 107) => Gcode::PumpOff,
+        ('M', 114) => Gcode::Position,
+        ('M', 400) => Gcode::Wait,
+        _ => return Err(()),
+    };
+    Ok(gcode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Gcode, ()> {
+        parse_gcode(line).map(|(_, gcode)| gcode).map_err(|_| ())
+    }
+
+    #[test]
+    fn home_takes_bare_axes() {
+        let xy = Gcode::Home(Axes {
+            x: true,
+            y: true,
+            z: false,
+        });
+        assert_eq!(parse("G28 X Y"), Ok(xy.clone()));
+        assert_eq!(parse("G28 X0 Y0"), Ok(xy));
+        assert_eq!(
+            parse("G28"),
+            Ok(Gcode::Home(Axes {
+                x: true,
+                y: true,
+                z: true,
+            }))
+        );
+        assert_eq!(parse("G1 X"), Err(()));
+        assert_eq!(parse("G28 F"), Err(()));
+        assert_eq!(parse("G"), Err(()));
+    }
+
+    #[test]
+    fn values_may_start_with_the_point() {
+        assert_eq!(
+            parse("G1 X.5 Y-.25 F600"),
+            Ok(Gcode::Move {
+                rapid: false,
+                target: Set {
+                    x: Some(500),
+                    y: Some(-250),
+                    z: None,
+                },
+                feed: Some(600_000),
+            })
+        );
+    }
+}
@@ -0,0 +1,31 @@
+//! Concrete pin and bus types the controller task is wired with.
+//!
+//! `Stepper`, `Pump` and `Storage` only need the `embedded-hal` traits and
+//! the schedule only needs `clock::Clock`, so porting to another board means
+//! changing the aliases below.
+
+#[cfg(target_os = "none")]
+pub type Pin = embassy_stm32::gpio::Output<'static, embassy_stm32::gpio::AnyPin>;
+#[cfg(all(target_os = "none", not(feature = "timer-steps")))]
+pub type StepOut = Pin;
+#[cfg(all(target_os = "none", feature = "timer-steps"))]
+pub type StepOut = crate::step_timer::TimerStepPin;
+#[cfg(target_os = "none")]
+pub type Input = embassy_stm32::gpio::Input<'static, embassy_stm32::gpio::AnyPin>;
+#[cfg(target_os = "none")]
+pub type I2cBus = embassy_stm32::i2c::I2c<'static, embassy_stm32::peripherals::I2C1>;
+#[cfg(target_os = "none")]
+pub type Rtc = embassy_stm32::rtc::Rtc;
+
+#[cfg(not(target_os = "none"))]
+pub type Pin = crate::sim::SimPin;
+#[cfg(not(target_os = "none"))]
+pub type StepOut = Pin;
+#[cfg(not(target_os = "none"))]
+pub type Input = crate::sim::SimInput;
+#[cfg(not(target_os = "none"))]
+pub type I2cBus = crate::sim::SimEeprom;
+#[cfg(not(target_os = "none"))]
+pub type Rtc = crate::sim::SimClock;
+
+impl crate::stepper::StepPin for Pin {}
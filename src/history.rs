@@ -0,0 +1,302 @@
+//! Watering history, a ring of one page records in the EEPROM.
+//!
+//! Every slot holds a sequence number and a CRC. There is no header to keep
+//! in step: the newest record is the valid one with the highest sequence, so
+//! a write torn by a power loss costs the slot being written, which held the
+//! oldest record anyway. `clear` writes a marker instead of erasing the ring.
+
+use crate::clock::{Date, TimeOfDay};
+use crate::event::Fault;
+use crate::storage::{crc16, Storage, CRC_INIT};
+use embassy_time::{Duration, Timer};
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+/// First EEPROM page of the ring, past the schedule table.
+const FIRST_PAGE: u8 = 110;
+/// Slots up to the last page of the 8 KiB EEPROM.
+const SLOTS: u8 = (255 - FIRST_PAGE as u16 + 1) as u8;
+/// Most entries `read` returns at once.
+pub const MAX_SHOW: usize = 50;
+
+/// One watering cycle.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    /// Counts up by one per record, survives wrapping and `clear`.
+    pub seq: u32,
+    /// Date at the start of the cycle, if the clock was running.
+    pub date: Option<Date>,
+    /// Time of day at the start of the cycle, if the clock was running.
+    pub at: Option<TimeOfDay>,
+    /// Seconds since power up at the start of the cycle.
+    pub uptime_s: u32,
+    pub planned: u8,
+    pub watered: u8,
+    /// Total pump on-time.
+    pub pump_ms: u32,
+    pub fault: Option<Fault>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+enum Record {
+    Cycle(Entry),
+    /// Everything older was cleared.
+    Cleared {
+        seq: u32,
+    },
+}
+
+impl Record {
+    fn seq(&self) -> u32 {
+        match self {
+            Record::Cycle(entry) => entry.seq,
+            Record::Cleared { seq } => *seq,
+        }
+    }
+}
+
+/// Position of the ring's head, found again on every power up.
+pub struct History {
+    /// Slot the next record goes to.
+    next_slot: u8,
+    next_seq: u32,
+}
+
+impl History {
+    /// Scan the ring for the newest record. Unreadable slots are skipped, an
+    /// empty or unreadable ring starts over at the first slot.
+    pub fn restore<I: Write + WriteRead>(sto: &mut Storage<I>) -> Self {
+        let mut newest: Option<(u8, u32)> = None;
+        for slot in 0..SLOTS {
+            if let Some(record) = read_slot(sto, slot) {
+                if newest.is_none_or(|(_, seq)| record.seq() > seq) {
+                    newest = Some((slot, record.seq()));
+                }
+            }
+        }
+        match newest {
+            Some((slot, seq)) => Self {
+                next_slot: (slot + 1) % SLOTS,
+                next_seq: seq.wrapping_add(1),
+            },
+            None => Self {
+                next_slot: 0,
+                next_seq: 0,
+            },
+        }
+    }
+
+    /// Record a cycle over the oldest slot, its `seq` is filled in here.
+    pub async fn push<I: Write + WriteRead>(
+        &mut self,
+        sto: &mut Storage<I>,
+        mut entry: Entry,
+    ) -> Result<(), ()> {
+        entry.seq = self.next_seq;
+        self.write(sto, Record::Cycle(entry)).await
+    }
+
+    /// Hide every record so far, `read` stops at the marker.
+    pub async fn clear<I: Write + WriteRead>(&mut self, sto: &mut Storage<I>) -> Result<(), ()> {
+        let seq = self.next_seq;
+        self.write(sto, Record::Cleared { seq }).await
+    }
+
+    /// Up to `n` of the newest cycles, newest first.
+    pub fn read<I: Write + WriteRead>(
+        &self,
+        sto: &mut Storage<I>,
+        n: usize,
+    ) -> Result<Vec<Entry, MAX_SHOW>, ()> {
+        let mut list = Vec::new();
+        let mut slot = self.next_slot;
+        let mut seq = self.next_seq;
+        for _ in 0..n.min(MAX_SHOW).min(SLOTS as usize) {
+            slot = slot.checked_sub(1).unwrap_or(SLOTS - 1);
+            seq = seq.wrapping_sub(1);
+            // A gap in the sequence is a torn write, or the start of the
+            // ring when it has not wrapped yet.
+            match read_slot(sto, slot) {
+                Some(Record::Cycle(entry)) if entry.seq == seq => {
+                    list.push(entry).map_err(|_| ())?
+                }
+                _ => break,
+            }
+        }
+        Ok(list)
+    }
+
+    async fn write<I: Write + WriteRead>(
+        &mut self,
+        sto: &mut Storage<I>,
+        record: Record,
+    ) -> Result<(), ()> {
+        let mut page = [0; 32];
+        let len = page.len() - 2;
+        postcard::to_slice(&record, &mut page[..len]).map_err(|_| ())?;
+        let crc = crc16(CRC_INIT, &page[..len]);
+        page[len..].copy_from_slice(&crc.to_le_bytes());
+        let written = sto.write_page(FIRST_PAGE + self.next_slot, page);
+        Timer::after(Duration::from_millis(10)).await;
+        // After a failed write the next record retries the slot, a gap in
+        // the sequence would hide everything older from `read`.
+        if written.is_ok() {
+            self.next_slot = (self.next_slot + 1) % SLOTS;
+            self.next_seq = self.next_seq.wrapping_add(1);
+        }
+        written
+    }
+}
+
+fn read_slot<I: Write + WriteRead>(sto: &mut Storage<I>, slot: u8) -> Option<Record> {
+    let page = sto.read_page(FIRST_PAGE + slot).ok()?;
+    let len = page.len() - 2;
+    if crc16(CRC_INIT, &page[..len]) != u16::from_le_bytes([page[len], page[len + 1]]) {
+        return None;
+    }
+    postcard::from_bytes(&page[..len]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{block_on, SimEeprom, SimI2cError};
+    use core::cell::Cell;
+
+    /// Refuses every write while `fail` is set.
+    struct Flaky<'a> {
+        mem: SimEeprom,
+        fail: &'a Cell<bool>,
+    }
+
+    impl Write for Flaky<'_> {
+        type Error = SimI2cError;
+
+        fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), SimI2cError> {
+            if self.fail.get() {
+                return Err(SimI2cError);
+            }
+            self.mem.write(address, bytes)
+        }
+    }
+
+    impl WriteRead for Flaky<'_> {
+        type Error = SimI2cError;
+
+        fn write_read(
+            &mut self,
+            address: u8,
+            bytes: &[u8],
+            buffer: &mut [u8],
+        ) -> Result<(), SimI2cError> {
+            self.mem.write_read(address, bytes, buffer)
+        }
+    }
+
+    fn entry(pump_ms: u32) -> Entry {
+        Entry {
+            seq: 0,
+            date: Date::new(2026, 10, 17),
+            at: TimeOfDay::new(6, 30, 0),
+            uptime_s: 3600,
+            planned: 4,
+            watered: 4,
+            pump_ms,
+            fault: None,
+        }
+    }
+
+    fn seqs<I: Write + WriteRead>(history: &History, sto: &mut Storage<I>) -> Vec<u32, MAX_SHOW> {
+        let list = history.read(sto, MAX_SHOW).unwrap();
+        list.iter().map(|entry| entry.seq).collect()
+    }
+
+    #[test]
+    fn blank_chip_starts_at_the_first_slot() {
+        let mut sto = Storage::new(SimEeprom::new());
+        let history = History::restore(&mut sto);
+        assert_eq!((history.next_slot, history.next_seq), (0, 0));
+        assert!(seqs(&history, &mut sto).is_empty());
+    }
+
+    #[test]
+    fn restore_finds_the_head_past_the_last_slot() {
+        let mut sto = Storage::new(SimEeprom::new());
+        let mut history = History::restore(&mut sto);
+        let n = SLOTS as u32 + 5;
+        for i in 0..n {
+            block_on(history.push(&mut sto, entry(i))).unwrap();
+        }
+        assert_eq!((history.next_slot, history.next_seq), (5, n));
+
+        let restored = History::restore(&mut sto);
+        assert_eq!((restored.next_slot, restored.next_seq), (5, n));
+        let list = restored.read(&mut sto, 3).unwrap();
+        assert_eq!(list.len(), 3);
+        assert_eq!(
+            list[0],
+            Entry {
+                seq: n - 1,
+                ..entry(n - 1)
+            }
+        );
+        let newest_first: Vec<u32, MAX_SHOW> = (n - MAX_SHOW as u32..n).rev().collect();
+        assert_eq!(seqs(&restored, &mut sto), newest_first);
+    }
+
+    #[test]
+    fn torn_slot_costs_one_record() {
+        let mut sto = Storage::new(SimEeprom::new());
+        let mut history = History::restore(&mut sto);
+        for i in 0..3 {
+            block_on(history.push(&mut sto, entry(i))).unwrap();
+        }
+        // Power lost while the third record was written.
+        let mut page = sto.read_page(FIRST_PAGE + 2).unwrap();
+        page[4] ^= 0xFF;
+        sto.write_page(FIRST_PAGE + 2, page).unwrap();
+
+        let mut restored = History::restore(&mut sto);
+        assert_eq!((restored.next_slot, restored.next_seq), (2, 2));
+        assert_eq!(seqs(&restored, &mut sto), [1, 0]);
+        block_on(restored.push(&mut sto, entry(9))).unwrap();
+        assert_eq!(seqs(&restored, &mut sto), [2, 1, 0]);
+    }
+
+    #[test]
+    fn clear_hides_older_records() {
+        let mut sto = Storage::new(SimEeprom::new());
+        let mut history = History::restore(&mut sto);
+        for i in 0..3 {
+            block_on(history.push(&mut sto, entry(i))).unwrap();
+        }
+        block_on(history.clear(&mut sto)).unwrap();
+        assert!(seqs(&history, &mut sto).is_empty());
+        block_on(history.push(&mut sto, entry(3))).unwrap();
+        assert_eq!(seqs(&history, &mut sto), [4]);
+
+        let restored = History::restore(&mut sto);
+        assert_eq!((restored.next_slot, restored.next_seq), (5, 5));
+        assert_eq!(seqs(&restored, &mut sto), [4]);
+    }
+
+    #[test]
+    fn failed_write_retries_the_slot() {
+        let fail = Cell::new(false);
+        let mut sto = Storage::new(Flaky {
+            mem: SimEeprom::new(),
+            fail: &fail,
+        });
+        let mut history = History::restore(&mut sto);
+        for i in 0..2 {
+            block_on(history.push(&mut sto, entry(i))).unwrap();
+        }
+        fail.set(true);
+        assert!(block_on(history.push(&mut sto, entry(2))).is_err());
+        assert_eq!((history.next_slot, history.next_seq), (2, 2));
+        fail.set(false);
+        block_on(history.push(&mut sto, entry(2))).unwrap();
+        assert_eq!(seqs(&history, &mut sto), [2, 1, 0]);
+    }
+}
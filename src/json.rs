@@ -0,0 +1,173 @@
+//! JSON lines mode of the USB serial port, for scripts.
+//!
+//! `mode json` on the console switches over and `mode text` switches back.
+//! Every input line is an object `{"cmd": <Cmd>}` with the command in serde's
+//! externally tagged form, e.g. `{"cmd":"ListPos"}` or
+//! `{"cmd":{"Goto":{"x":12500,"y":0}}}`. Positions are in micrometers, as
+//! everywhere inside the firmware. Every reply is one line
+//! `{"ok":true,"error":null,"data":...}` where `data` is the reply text, or
+//! an array of positions for `ListPos` or of cycles for `LogShow`. After
+//! `{"cmd":"Subscribe"}` events arrive in between as `{"event":...}` lines.
+
+use crate::command::{Cmd, CmdError, ErrorCode};
+use crate::controller::{Answer, Reply, WateringPosition};
+use crate::event::Event;
+use crate::history::Entry;
+use serde::{Deserialize, Serialize};
+
+/// Longest input line.
+pub const MAX_REQUEST: usize = 256;
+/// Longest reply line: a full text reply with escapes, or 100 positions.
+pub const MAX_LINE: usize = 8192;
+
+#[derive(Deserialize)]
+struct Request {
+    cmd: Cmd,
+}
+
+#[derive(Serialize)]
+struct Response<'a> {
+    ok: bool,
+    error: Option<Error<'a>>,
+    data: Option<Data<'a>>,
+}
+
+#[derive(Serialize)]
+struct Error<'a> {
+    code: u8,
+    msg: &'a str,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Data<'a> {
+    Text(&'a str),
+    Positions(&'a [WateringPosition]),
+    History(&'a [Entry]),
+}
+
+/// Decode one input line.
+pub fn parse(line: &str) -> Result<Cmd, CmdError> {
+    serde_json_core::from_str::<Request>(line)
+        .map(|(request, _)| request.cmd)
+        .map_err(|_| CmdError::new(ErrorCode::Parse, "bad json"))
+}
+
+/// Encode `reply` as one line into `out`, newline included. Returns its
+/// length.
+pub fn encode(reply: &Reply, out: &mut [u8; MAX_LINE]) -> usize {
+    let line = &mut out[..MAX_LINE - 1];
+    let n = write(reply, line).unwrap_or_else(|| {
+        let too_long = Err(CmdError::new(ErrorCode::TooLong, "reply too long"));
+        write(&too_long, line).unwrap_or(0)
+    });
+    out[n] = b'\n';
+    n + 1
+}
+
+/// Encode `event` as one line into `out`, newline included.
+pub fn encode_event(event: &Event, out: &mut [u8; MAX_LINE]) -> usize {
+    #[derive(Serialize)]
+    struct Push<'a> {
+        event: &'a Event,
+    }
+    let n = serde_json_core::to_slice(&Push { event }, &mut out[..MAX_LINE - 1]).unwrap_or(0);
+    out[n] = b'\n';
+    n + 1
+}
+
+fn write(reply: &Reply, out: &mut [u8]) -> Option<usize> {
+    let response = match reply {
+        Ok(answer) => Response {
+            ok: true,
+            error: None,
+            data: Some(match answer {
+                Answer::Text(text) => Data::Text(text),
+                Answer::Positions(list) => Data::Positions(list),
+                Answer::History(list) => Data::History(list),
+            }),
+        },
+        Err(err) => Response {
+            ok: false,
+            error: Some(Error {
+                code: err.code as u8,
+                msg: &err.msg,
+            }),
+            data: None,
+        },
+    };
+    serde_json_core::to_slice(&response, out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Set;
+    use heapless::String;
+
+    fn line(out: &[u8; MAX_LINE], n: usize) -> &str {
+        core::str::from_utf8(&out[..n]).unwrap()
+    }
+
+    #[test]
+    fn parse_leaves_missing_axes_out() {
+        let cmd = parse(r#"{"cmd":{"Goto":{"x":12500}}}"#).unwrap();
+        assert_eq!(
+            cmd,
+            Cmd::Goto(Set {
+                x: Some(12_500),
+                ..Set::default()
+            })
+        );
+        assert_eq!(parse(r#"{"cmd":"ListPos"}"#).unwrap(), Cmd::ListPos);
+        assert_eq!(parse("ListPos").unwrap_err().code, ErrorCode::Parse);
+    }
+
+    #[test]
+    fn encode_wraps_replies() {
+        let mut out = [0; MAX_LINE];
+        let reply = Ok(Answer::Text(String::from("ready")));
+        let n = encode(&reply, &mut out);
+        assert_eq!(
+            line(&out, n),
+            "{\"ok\":true,\"error\":null,\"data\":\"ready\"}\n"
+        );
+
+        let positions = [WateringPosition {
+            x: 1000,
+            y: 2000,
+            z: 0,
+            dur_ms: 500,
+            group: 1,
+        }];
+        let reply = Ok(Answer::Positions(
+            heapless::Vec::from_slice(&positions).unwrap(),
+        ));
+        let n = encode(&reply, &mut out);
+        assert_eq!(
+            line(&out, n),
+            "{\"ok\":true,\"error\":null,\"data\":\
+             [{\"x\":1000,\"y\":2000,\"z\":0,\"dur_ms\":500,\"group\":1}]}\n"
+        );
+
+        let reply = Err(CmdError::new(ErrorCode::OutOfRange, "index out of range"));
+        let n = encode(&reply, &mut out);
+        assert_eq!(
+            line(&out, n),
+            "{\"ok\":false,\"error\":{\"code\":3,\"msg\":\"index out of range\"},\"data\":null}\n"
+        );
+    }
+
+    #[test]
+    fn oversized_reply_falls_back_to_too_long() {
+        // Every quote doubles when escaped, past the line buffer.
+        let mut text = String::new();
+        while text.push('"').is_ok() {}
+        let mut out = [0; MAX_LINE];
+        let n = encode(&Ok(Answer::Text(text)), &mut out);
+        assert_eq!(
+            line(&out, n),
+            "{\"ok\":false,\"error\":{\"code\":12,\"msg\":\"reply too long\"},\"data\":null}\n"
+        );
+    }
+}
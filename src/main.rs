@@ -1,30 +1,62 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(target_os = "none", no_std)]
+#![cfg_attr(target_os = "none", no_main)]
 #![feature(type_alias_impl_trait)]
 
+mod fmt;
+
+mod clock;
 mod command;
 mod controller;
+mod event;
+// The USB serial port is firmware only, the host build runs the tests.
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+mod frame;
+mod gcode;
+mod hal;
+mod history;
+#[cfg_attr(not(target_os = "none"), allow(dead_code))]
+mod json;
+mod profile;
 mod pump;
+mod schedule;
+#[cfg(target_os = "none")]
 mod serial;
+#[cfg(not(target_os = "none"))]
+mod sim;
+#[cfg(all(target_os = "none", feature = "timer-steps"))]
+mod step_timer;
 mod stepper;
 mod storage;
 
+#[cfg(target_os = "none")]
 use defmt_rtt as _;
 use embassy_executor::Spawner;
+#[cfg(target_os = "none")]
 use embassy_stm32::dma::NoDma;
-use embassy_stm32::gpio::{Level, Output, Speed};
+#[cfg(target_os = "none")]
+use embassy_stm32::gpio::{Input, Level, Output, Pin, Pull, Speed};
+#[cfg(target_os = "none")]
 use embassy_stm32::time::Hertz;
+#[cfg(target_os = "none")]
 use embassy_stm32::{bind_interrupts, peripherals, Config};
+#[cfg(target_os = "none")]
 use embassy_time::{Duration, Timer};
+#[cfg(target_os = "none")]
 use panic_probe as _;
+#[cfg(target_os = "none")]
 use storage::Storage;
+#[cfg(all(target_os = "none", feature = "estop-input"))]
+use {core::sync::atomic::Ordering, embassy_stm32::exti::ExtiInput};
 
+#[cfg(target_os = "none")]
 use crate::stepper::Stepper;
 
+#[cfg(target_os = "none")]
 bind_interrupts!(struct Irqs {
     I2C1_EV => embassy_stm32::i2c::InterruptHandler<peripherals::I2C1>;
 });
 
+#[cfg(target_os = "none")]
 #[embassy_executor::main]
 async fn main(_spawner: Spawner) {
     let mut config = Config::default();
@@ -43,6 +75,13 @@ async fn main(_spawner: Spawner) {
     let dir_pin1 = Output::new(p.PA5, Level::Low, Speed::Medium).degrade();
     let dir_pin2 = Output::new(p.PA6, Level::Low, Speed::Medium).degrade();
     let dir_pin3 = Output::new(p.PA7, Level::Low, Speed::Medium).degrade();
+    let endstop1 = Input::new(p.PB12.degrade(), Pull::Up);
+    let endstop2 = Input::new(p.PB13.degrade(), Pull::Up);
+    let endstop3 = Input::new(p.PB14.degrade(), Pull::Up);
+
+    #[cfg(feature = "timer-steps")]
+    let [step_pin1, step_pin2, step_pin3] =
+        step_timer::init((p.TIM3, p.TIM4, p.TIM5), [step_pin1, step_pin2, step_pin3]);
 
     {
         // BluePill board has a pull-up resistor on the D+ line.
@@ -66,14 +105,22 @@ async fn main(_spawner: Spawner) {
         Hertz(400_000),
         i2c_cfg,
     );
+    let rtc = embassy_stm32::rtc::Rtc::new(p.RTC, embassy_stm32::rtc::RtcConfig::default());
     _spawner.must_spawn(serial::serial(p.USB_OTG_FS, p.PA12, p.PA11));
 
+    #[cfg(feature = "estop-input")]
+    _spawner.must_spawn(estop_input(ExtiInput::new(
+        Input::new(p.PB15, Pull::Up),
+        p.EXTI15,
+    )));
+
     _spawner.must_spawn(controller::run(
-        Stepper::new(dir_pin1, step_pin1),
-        Stepper::new(dir_pin2, step_pin2),
-        Stepper::new(dir_pin3, step_pin3),
+        Stepper::new(dir_pin1, step_pin1, Some(endstop1)),
+        Stepper::new(dir_pin2, step_pin2, Some(endstop2)),
+        Stepper::new(dir_pin3, step_pin3, Some(endstop3)),
         Storage::new(i2c),
         pump::Pump::new(pump_pin),
+        rtc,
     ));
 
     loop {
@@ -81,3 +128,22 @@ async fn main(_spawner: Spawner) {
         Timer::after(Duration::from_millis(1000)).await;
     }
 }
+
+/// An open loop, pressed button or broken wire latches an emergency stop.
+#[cfg(all(target_os = "none", feature = "estop-input"))]
+#[embassy_executor::task]
+async fn estop_input(mut input: ExtiInput<'static, peripherals::PB15>) {
+    loop {
+        input.wait_for_high().await;
+        controller::ESTOP_INPUT.store(true, Ordering::Relaxed);
+        controller::emergency_stop();
+        input.wait_for_low().await;
+        controller::ESTOP_INPUT.store(false, Ordering::Relaxed);
+    }
+}
+
+#[cfg(not(target_os = "none"))]
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    sim::start(spawner);
+}
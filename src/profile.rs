@@ -0,0 +1,323 @@
+//! Trapezoidal motion profile as a sequence of step intervals.
+//!
+//! The profile knows nothing about pins or timers, `stepper` pulses the step
+//! line and waits for each interval it yields. Speeds are in steps per
+//! second, acceleration in steps per second squared and jerk in steps per
+//! second cubed.
+
+/// Slowest speed a profile runs at, keeps every interval finite.
+pub const MIN_SPS: f32 = 1.0;
+
+/// Step intervals of one move, in microseconds.
+///
+/// The speed starts at `min_sps` and ramps up by `accel` towards `max_sps`.
+/// It ramps down over as many steps as the ramp up took, so a move too short
+/// to reach `max_sps` turns into a triangle peaking halfway.
+///
+/// With a jerk limit the acceleration itself ramps between zero and `accel`,
+/// rounding off the corners of the trapezoid into an S-curve.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    steps: u32,
+    done: u32,
+    /// Steps spent accelerating so far.
+    ramp: u32,
+    sps: f32,
+    min_sps: f32,
+    max_sps: f32,
+    accel: f32,
+    /// Zero for a plain trapezoid.
+    jerk: f32,
+    /// Current acceleration, only used with a jerk limit.
+    a: f32,
+    /// Speed the last accelerating step would have reached without the
+    /// `max_sps` cap, the ramp down starts over from it.
+    top: f32,
+}
+
+impl Profile {
+    /// Nonsensical parameters are clamped: speeds to at least `MIN_SPS`,
+    /// `max_sps` to at least `min_sps` and a negative or NaN `accel` to zero,
+    /// which runs the whole move at `min_sps`.
+    pub fn new(steps: u32, min_sps: f32, max_sps: f32, accel: f32) -> Self {
+        let min_sps = finite_or(min_sps, MIN_SPS).max(MIN_SPS);
+        let max_sps = finite_or(max_sps, min_sps).max(min_sps);
+        let accel = finite_or(accel, 0.0).max(0.0);
+        Self {
+            steps,
+            done: 0,
+            ramp: 0,
+            sps: min_sps,
+            min_sps,
+            max_sps,
+            accel,
+            jerk: 0.0,
+            a: 0.0,
+            top: min_sps,
+        }
+    }
+
+    /// Limit the rate of change of the acceleration, zero keeps the
+    /// trapezoid.
+    pub fn with_jerk(mut self, jerk: f32) -> Self {
+        self.jerk = finite_or(jerk, 0.0).max(0.0);
+        self
+    }
+
+    /// Steps this profile yields in total, fewer after `stop`.
+    pub fn steps(&self) -> u32 {
+        self.steps
+    }
+
+    /// Steps yielded so far.
+    pub fn done(&self) -> u32 {
+        self.done
+    }
+
+    /// Current speed, the one the next interval is taken from.
+    pub fn sps(&self) -> f32 {
+        self.sps
+    }
+
+    /// Cut the move short: ramp down right away and end once back at
+    /// `min_sps`.
+    pub fn stop(&mut self) {
+        // The ramp down retraces the speeds of the ramp up, the current one
+        // included.
+        let down = if self.ramp > 0 { self.ramp + 1 } else { 0 };
+        self.steps = self.steps.min(self.done + down);
+    }
+}
+
+impl Iterator for Profile {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.done >= self.steps {
+            return None;
+        }
+        let period = 1.0 / self.sps;
+        self.done += 1;
+        let left = self.steps - self.done;
+        let slowing = left <= self.ramp;
+        if slowing {
+            // Give back one accelerating step per decelerating one.
+            self.ramp = left.min(self.ramp);
+        }
+        if self.jerk > 0.0 {
+            self.s_curve(slowing, left, period);
+        } else if slowing {
+            self.sps = self.slower(left, -self.accel);
+        } else if left > self.ramp + 1 && self.sps < self.max_sps && self.accel > 0.0 {
+            // With exactly `ramp + 1` steps left the speed holds for one
+            // step, so a triangle of even length peaks twice.
+            self.top = self.sps + self.accel * period;
+            self.sps = self.top.min(self.max_sps);
+            self.ramp += 1;
+        }
+        Some(((period * 1_000_000.0) as u32).max(1))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = (self.steps - self.done) as usize;
+        (left, Some(left))
+    }
+}
+
+impl Profile {
+    /// Speed after one step slowing at `a`, which is negative.
+    ///
+    /// The ramp up adds `a` times the interval of the step, the ramp down
+    /// solves `v = sps + a / v` for the interval of the next step instead,
+    /// which undoes a step of the ramp up exactly. The last one lands on
+    /// `min_sps`, the ramp up left it with a bigger step than the equation
+    /// can tell apart.
+    fn slower(&self, left: u32, a: f32) -> f32 {
+        if left <= 1 {
+            return self.min_sps;
+        }
+        let sps = if self.sps >= self.max_sps {
+            self.top
+        } else {
+            self.sps
+        };
+        let root = sqrt((1.0 + 4.0 * a / sps / sps).max(0.0));
+        (sps * (1.0 + root) / 2.0).clamp(self.min_sps, self.max_sps)
+    }
+
+    /// Jerk limited speed update. The acceleration moves towards `accel`,
+    /// zero or `-accel` by at most `jerk * period`, and eases back to zero
+    /// early enough to land on `max_sps` or `min_sps` without overshoot.
+    fn s_curve(&mut self, slowing: bool, left: u32, period: f32) {
+        let (target, gap) = if slowing {
+            // A short move peaks with acceleration left, mirror it so the
+            // ramp down takes as many steps as the ramp up.
+            self.a = -self.a.abs();
+            (-self.accel, self.sps - self.min_sps)
+        } else {
+            (self.accel, self.max_sps - self.sps)
+        };
+        let target = if self.a * self.a / (2.0 * self.jerk) >= gap {
+            0.0
+        } else {
+            target
+        };
+        let da = self.jerk * period;
+        self.a = if self.a < target {
+            (self.a + da).min(target)
+        } else {
+            (self.a - da).max(target)
+        };
+        if slowing {
+            self.sps = self.slower(left, self.a);
+            return;
+        }
+        self.top = self.sps + self.a * period;
+        let sps = self.top.clamp(self.min_sps, self.max_sps);
+        if sps > self.sps {
+            self.ramp += 1;
+        }
+        self.sps = sps;
+    }
+}
+
+/// Square root of a finite `x` from 0 to 1, `core` has none.
+fn sqrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    // Halving the exponent bits is a guess within a few percent, Newton
+    // steps square the error away.
+    let mut r = f32::from_bits((x.to_bits() >> 1) + 0x1fbd_1df5);
+    for _ in 0..3 {
+        r = (r + x / r) / 2.0;
+    }
+    r
+}
+
+fn finite_or(v: f32, default: f32) -> f32 {
+    if v.is_finite() {
+        v
+    } else {
+        default
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn periods(profile: Profile) -> Vec<u32> {
+        profile.collect()
+    }
+
+    /// Smallest interval, the peak speed.
+    fn peak_sps(periods: &[u32]) -> f32 {
+        1_000_000.0 / *periods.iter().min().unwrap() as f32
+    }
+
+    fn assert_symmetric(periods: &[u32]) {
+        let n = periods.len();
+        for i in 0..n / 2 {
+            let (up, down) = (periods[i] as f32, periods[n - 1 - i] as f32);
+            assert!(
+                (up - down).abs() <= up * 0.05 + 1.0,
+                "step {} of {}: {} up, {} down",
+                i,
+                n,
+                up,
+                down
+            );
+        }
+    }
+
+    #[test]
+    fn zero_length_move_yields_nothing() {
+        assert_eq!(Profile::new(0, 100.0, 1000.0, 5000.0).next(), None);
+        assert_eq!(
+            Profile::new(0, 100.0, 1000.0, 5000.0).with_jerk(1e5).next(),
+            None
+        );
+    }
+
+    #[test]
+    fn short_move_is_a_triangle() {
+        let p = periods(Profile::new(40, 100.0, 10_000.0, 20_000.0));
+        assert_eq!(p.len(), 40);
+        assert!(peak_sps(&p) < 10_000.0);
+        // Fastest in the middle, slowest at both ends.
+        let fastest = p.iter().enumerate().min_by_key(|(_, p)| **p).unwrap().0;
+        assert!((18..=21).contains(&fastest), "peak at step {}", fastest);
+        assert_eq!(p[0], 10_000);
+        assert_symmetric(&p);
+    }
+
+    #[test]
+    fn long_move_is_a_trapezoid() {
+        let p = periods(Profile::new(2000, 100.0, 1000.0, 5000.0));
+        assert_eq!(p.len(), 2000);
+        assert!(peak_sps(&p) <= 1000.0 * 1.001);
+        // A cruise phase at `max_sps` in the middle.
+        assert!(p[900..1100].iter().all(|&p| p == 1000));
+        assert_symmetric(&p);
+    }
+
+    #[test]
+    fn jerk_limited_move() {
+        let p = periods(Profile::new(3000, 100.0, 1000.0, 5000.0).with_jerk(50_000.0));
+        assert_eq!(p.len(), 3000);
+        assert!(peak_sps(&p) <= 1000.0 * 1.001);
+        // Ramps of about the same length around the cruise, each one
+        // monotonic.
+        let up = p.iter().position(|&p| p == 1000).unwrap();
+        let down = p.len() - 1 - p.iter().rposition(|&p| p == 1000).unwrap();
+        assert!(
+            up.abs_diff(down) <= up / 20,
+            "{} steps up, {} down",
+            up,
+            down
+        );
+        assert!(p[..up].windows(2).all(|w| w[0] >= w[1]));
+        assert!(p[p.len() - down..].windows(2).all(|w| w[0] <= w[1]));
+        assert_eq!(p[0], 10_000);
+        assert_eq!(p[p.len() - 1], 10_000);
+        // Short enough to peak while still accelerating.
+        let p = periods(Profile::new(30, 100.0, 10_000.0, 20_000.0).with_jerk(1e6));
+        assert_eq!(p.len(), 30);
+        assert!(peak_sps(&p) < 10_000.0);
+    }
+
+    #[test]
+    fn extreme_parameters_stay_finite() {
+        let cases = [
+            (0.0, 0.0, 0.0, 0.0),
+            (-5.0, -1.0, -100.0, -1.0),
+            (f32::NAN, f32::NAN, f32::NAN, f32::NAN),
+            (f32::INFINITY, f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            (1000.0, 10.0, 1e9, 0.0),
+            (1e-9, 1e9, 1e12, 1e15),
+            (f32::MAX, f32::MAX, f32::MAX, f32::MAX),
+        ];
+        for (min_sps, max_sps, accel, jerk) in cases {
+            for steps in [1, 2, 3, 1000] {
+                let profile = Profile::new(steps, min_sps, max_sps, accel).with_jerk(jerk);
+                let max = profile.max_sps;
+                let p = periods(profile);
+                assert_eq!(p.len(), steps as usize, "{:?}", (min_sps, max_sps, accel));
+                // `MIN_SPS` bounds every interval at one second.
+                assert!(p.iter().all(|&p| (1..=1_000_000).contains(&p)));
+                assert!(peak_sps(&p) <= max * 1.001 + 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn stop_ramps_down() {
+        let mut profile = Profile::new(10_000, 100.0, 1000.0, 5000.0);
+        let up: Vec<u32> = profile.by_ref().take(500).collect();
+        profile.stop();
+        let down = periods(profile);
+        assert!(down.len() < 500);
+        assert_eq!(*down.last().unwrap(), *up.first().unwrap());
+    }
+}
@@ -1,17 +1,69 @@
-use embassy_stm32::gpio::{AnyPin, Output};
+use crate::event::{self, Event};
+use crate::stepper::ESTOP;
+use core::sync::atomic::Ordering;
+use embassy_time::Instant;
+use embedded_hal::digital::v2::OutputPin;
 
-pub struct Pump {
-    pin: Output<'static, AnyPin>,
+pub struct Pump<P> {
+    pin: P,
+    on: bool,
+    /// When the pump last switched on.
+    since: Instant,
 }
 
-impl Pump {
-    pub fn new(pin: Output<'static, AnyPin>) -> Self {
-        Self { pin }
+impl<P: OutputPin> Pump<P> {
+    pub fn new(pin: P) -> Self {
+        Self {
+            pin,
+            on: false,
+            since: Instant::from_ticks(0),
+        }
     }
+    /// Ignored while an E-stop is latched.
     pub fn on(&mut self) {
-        self.pin.set_high();
+        if ESTOP.load(Ordering::Relaxed) {
+            return;
+        }
+        self.pin.set_high().ok();
+        if !self.on {
+            self.since = Instant::now();
+            event::publish(Event::PumpOn);
+        }
+        self.on = true;
     }
     pub fn off(&mut self) {
-        self.pin.set_low();
+        self.pin.set_low().ok();
+        if self.on {
+            let ms = self.since.elapsed().as_millis();
+            event::publish(Event::PumpOff {
+                ms: u32::try_from(ms).unwrap_or(u32::MAX),
+            });
+        }
+        self.on = false;
+    }
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{PinLog, SimPin};
+
+    #[test]
+    fn drives_the_mock_pin() {
+        static PIN: PinLog = PinLog::new();
+        let mut pump = Pump::new(SimPin::new(&PIN));
+        assert!(!pump.is_on());
+
+        pump.on();
+        pump.on();
+        assert!(pump.is_on());
+        assert!(PIN.is_high());
+        pump.off();
+        assert!(!pump.is_on());
+        assert!(!PIN.is_high());
+        assert_eq!(PIN.rising(), 1);
     }
 }
@@ -0,0 +1,112 @@
+use heapless::Vec;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_ENTRIES: usize = 8;
+
+/// Fires at `start`, then every `every` minutes up to and including `end`,
+/// watering the positions of `group`. Times are minutes since midnight,
+/// `every == 0` fires once at `start`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub start: u16,
+    pub end: u16,
+    pub every: u16,
+    pub group: u8,
+}
+
+/// `Entry` as stored before groups existed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryV1 {
+    pub start: u16,
+    pub end: u16,
+    pub every: u16,
+}
+
+impl From<EntryV1> for Entry {
+    fn from(e: EntryV1) -> Self {
+        Self {
+            start: e.start,
+            end: e.end,
+            every: e.every,
+            group: 0,
+        }
+    }
+}
+
+impl Entry {
+    pub fn at(minute: u16, group: u8) -> Self {
+        Self {
+            start: minute,
+            end: minute,
+            every: 0,
+            group,
+        }
+    }
+
+    pub fn is_due(&self, minute: u16) -> bool {
+        if minute < self.start || minute > self.end {
+            return false;
+        }
+        match self.every {
+            0 => minute == self.start,
+            every => (minute - self.start).is_multiple_of(every),
+        }
+    }
+}
+
+pub type Table = Vec<Entry, MAX_ENTRIES>;
+
+/// Groups with an entry firing in `minute`.
+pub fn due_groups(table: &Table, minute: u16) -> Vec<u8, MAX_ENTRIES> {
+    let mut groups = Vec::new();
+    for e in table.iter().filter(|e| e.is_due(minute)) {
+        if !groups.contains(&e.group) {
+            groups.push(e.group).ok();
+        }
+    }
+    groups
+}
+
+/// Groups with an entry firing after minute `last` up to and including `now`,
+/// across midnight if `now` is the smaller.
+pub fn due_groups_since(table: &Table, last: u16, now: u16) -> Vec<u8, MAX_ENTRIES> {
+    let mut groups = Vec::new();
+    let mut minute = last;
+    while minute != now {
+        minute = (minute + 1) % (24 * 60);
+        for group in due_groups(table, minute) {
+            if !groups.contains(&group) {
+                groups.push(group).ok();
+            }
+        }
+    }
+    groups
+}
+
+/// Minutes from `minute` until the next entry fires, at most a day ahead.
+pub fn next_due(table: &Table, minute: u16) -> Option<u16> {
+    (1..=24 * 60).find(|ahead| {
+        let at = (minute + ahead) % (24 * 60);
+        table.iter().any(|e| e.is_due(at))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catches_up_on_skipped_minutes() {
+        let table = Table::from_slice(&[
+            Entry::at(6 * 60, 1),
+            Entry::at(6 * 60 + 5, 2),
+            Entry::at(6 * 60 + 5, 1),
+            Entry::at(0, 3),
+        ])
+        .unwrap();
+        assert_eq!(due_groups_since(&table, 6 * 60, 6 * 60 + 4), []);
+        assert_eq!(due_groups_since(&table, 6 * 60 - 1, 6 * 60 + 10), [1, 2]);
+        assert_eq!(due_groups_since(&table, 6 * 60, 6 * 60), []);
+        assert_eq!(due_groups_since(&table, 23 * 60 + 50, 6 * 60), [3, 1]);
+    }
+}
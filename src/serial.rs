@@ -1,4 +1,5 @@
-use defmt::info;
+use core::fmt::Write;
+use core::pin::pin;
 use embassy_stm32::{
     bind_interrupts, peripherals,
     usb_otg::{Driver, Instance},
@@ -9,10 +10,14 @@ use embassy_usb::{
     driver::EndpointError,
     Builder,
 };
-use futures::future::join;
-use heapless::Vec;
+use futures::future::{join, select, Either};
+use heapless::{String, Vec};
 
-use crate::controller::{self};
+use crate::command::{Cmd, CmdError, ErrorCode};
+use crate::controller::{Answer, Client, Reply};
+use crate::event::{self, Event, Events};
+use crate::frame::{self, Body, Framer, Op, Request, Response};
+use crate::json;
 
 bind_interrupts!(struct Irqs {
     OTG_FS => embassy_stm32::usb_otg::InterruptHandler<peripherals::USB_OTG_FS>;
@@ -92,48 +97,328 @@ impl From<EndpointError> for Disconnected {
     }
 }
 
+/// What the port speaks, switched by `mode json`, `mode text` and
+/// `frame::HANDSHAKE`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Text,
+    Json,
+    Binary,
+}
+
+/// How a command came in, and so how its reply goes out. The mode may have
+/// changed by the time the reply is ready.
+#[derive(Clone, Copy)]
+enum ReplyTo {
+    Text,
+    Json,
+    /// Binary request with this id.
+    Frame(u16),
+}
+
+/// Commands read while another one runs, waiting for their turn.
+const MAX_QUEUED: usize = 4;
+
+type Link = Client<ReplyTo, MAX_QUEUED>;
+
+/// What woke the port up.
+enum Wake {
+    Read(usize),
+    Event(Event),
+    Reply(Reply, ReplyTo),
+}
+
 async fn handle<'d, T: Instance + 'd>(
     class: &mut CdcAcmClass<'d, Driver<'d, T>>,
 ) -> Result<(), Disconnected> {
     let mut buf = [0; 64];
-    let mut sbuf = Vec::<u8, 32>::new();
+    let mut sbuf = Vec::<u8, { json::MAX_REQUEST }>::new();
+    let mut mode = Mode::Text;
+    let mut framer = Framer::new();
+    let mut line = [0; json::MAX_LINE];
+    let mut events = None;
+    let mut client = Link::new();
     Timer::after(Duration::from_millis(100)).await;
 
     loop {
-        let n = class.read_packet(&mut buf).await?;
+        // Input is read on while a command runs, so a stop typed meanwhile
+        // still cuts it short.
+        let wake = match select(
+            pin!(class.read_packet(&mut buf)),
+            select(pin!(next_event(&mut events)), pin!(client.reply())),
+        )
+        .await
+        {
+            Either::Left((n, _)) => Wake::Read(n?),
+            Either::Right((Either::Left((event, _)), _)) => Wake::Event(event),
+            Either::Right((Either::Right(((reply, to), _)), _)) => Wake::Reply(reply, to),
+        };
+        let n = match wake {
+            Wake::Read(n) => n,
+            Wake::Reply(reply, to) => {
+                answer(class, &mut framer, &mut line, to, reply).await?;
+                continue;
+            }
+            Wake::Event(event) => {
+                match mode {
+                    Mode::Text => {
+                        let mut msg = String::<80>::new();
+                        write!(msg, "[EVENT {}]\n\r", event).ok();
+                        write_all(class, msg.as_bytes()).await?;
+                    }
+                    Mode::Json => {
+                        let n = json::encode_event(&event, &mut line);
+                        write_all(class, &line[..n]).await?;
+                    }
+                    Mode::Binary => {
+                        let push = Response {
+                            id: 0,
+                            body: Body::Event(&event),
+                        };
+                        write_all(class, framer.encode(&push)).await?;
+                    }
+                }
+                continue;
+            }
+        };
         for b in &buf[..n] {
-            match *b {
-                b'\x7f' | b'\x08' => {
+            match (mode, *b) {
+                (Mode::Binary, b) => {
+                    if let Some(request) = framer.push(b) {
+                        let binary =
+                            frame_reply(class, &mut framer, &mut events, &mut client, request)
+                                .await?;
+                        if !binary {
+                            mode = Mode::Text;
+                        }
+                    }
+                }
+                (Mode::Json, b'\x0d' | b'\x0a') => {
+                    let Ok(st) = core::str::from_utf8(&sbuf) else {
+                        sbuf = Vec::new();
+                        continue;
+                    };
+                    let st = st.trim();
+                    if st.is_empty() {
+                        sbuf.clear();
+                        continue;
+                    }
+                    info!("json: {}", st);
+                    let reply = if st.eq_ignore_ascii_case("mode text") {
+                        mode = Mode::Text;
+                        Some(Ok(Answer::default()))
+                    } else {
+                        match json::parse(st) {
+                            Ok(cmd) => dispatch(cmd, ReplyTo::Json, &mut events, &mut client),
+                            Err(err) => Some(Err(err)),
+                        }
+                    };
+                    if let Some(reply) = reply {
+                        answer(class, &mut framer, &mut line, ReplyTo::Json, reply).await?;
+                    }
+                    sbuf = Vec::new();
+                }
+                (Mode::Json, b' '..=b'~') => {
+                    sbuf.push(*b).ok();
+                }
+                (Mode::Json, _) => {}
+                (Mode::Text, frame::HANDSHAKE) => {
+                    info!("binary mode");
+                    mode = Mode::Binary;
+                    sbuf = Vec::new();
+                    framer.clear();
+                    let pong = Response {
+                        id: 0,
+                        body: Body::Pong {
+                            version: frame::VERSION,
+                        },
+                    };
+                    write_all(class, framer.encode(&pong)).await?;
+                }
+                (Mode::Text, b'\x7f' | b'\x08') => {
                     if sbuf.pop().is_some() {
                         class.write_packet(b"\x08\x1b[K").await?;
                     }
                 }
-                b'\x0d' | b'\x0a' => {
+                (Mode::Text, b'\x0d' | b'\x0a') => {
                     if let Ok(st) = core::str::from_utf8(&sbuf) {
                         info!("data: {}", st);
                         class.write_packet(b"\x0A\x0D").await?;
-                        if let Ok((_, cmd)) = crate::command::parse_cmd(st) {
-                            let ret = controller::send_msg(cmd).await;
-                            for c in ret.as_bytes() {
-                                if *c == b'\n' {
-                                    class.write_packet(&[b'\r', b'\n']).await?;
-                                } else {
-                                    class.write_packet(&[*c]).await?;
+                        let ret = if st.trim().eq_ignore_ascii_case("mode json") {
+                            mode = Mode::Json;
+                            Some(Ok(Answer::default()))
+                        } else {
+                            match crate::command::parse_cmd(st) {
+                                Ok((_, cmd)) => {
+                                    dispatch(cmd, ReplyTo::Text, &mut events, &mut client)
                                 }
+                                Err(_) => Some(Err(CmdError::new(ErrorCode::Parse, "parse fail"))),
                             }
-                            class.write_packet(b"[OK]\x0A\x0D").await?;
-                        } else {
-                            class.write_packet(b"[Parse fail]\x0A\x0D").await?;
+                        };
+                        if let Some(ret) = ret {
+                            answer(class, &mut framer, &mut line, ReplyTo::Text, ret).await?;
                         }
                     }
                     sbuf = Vec::new();
                 }
-                b'0'..=b'9' | b' ' | b'a'..=b'z' | b'A'..=b'Z' | b'-' => {
+                (
+                    Mode::Text,
+                    b'0'..=b'9'
+                    | b' '
+                    | b'a'..=b'z'
+                    | b'A'..=b'Z'
+                    | b'-'
+                    | b':'
+                    | b'.'
+                    | b';'
+                    | b'('
+                    | b')'
+                    | b'*',
+                ) => {
                     sbuf.push(*b).ok();
                     class.write_packet(&[*b]).await?;
                 }
-                _ => {}
+                (Mode::Text, _) => {}
             }
         }
     }
 }
+
+/// Answer one binary request, commands for the controller answer later.
+/// Returns whether to stay in binary mode.
+async fn frame_reply<'d, T: Instance + 'd>(
+    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+    framer: &mut Framer,
+    events: &mut Option<Events>,
+    client: &mut Link,
+    request: Result<Request, ()>,
+) -> Result<bool, Disconnected> {
+    let Ok(request) = request else {
+        let bad = Response {
+            id: 0,
+            body: Body::BadFrame,
+        };
+        write_all(class, framer.encode(&bad)).await?;
+        return Ok(true);
+    };
+    let id = request.id;
+    match request.op {
+        Op::Cmd(cmd) => {
+            if let Some(reply) = dispatch(cmd, ReplyTo::Frame(id), events, client) {
+                let response = Response {
+                    id,
+                    body: Body::reply(&reply),
+                };
+                write_all(class, framer.encode(&response)).await?;
+            }
+            Ok(true)
+        }
+        Op::Ping => {
+            let pong = Response {
+                id,
+                body: Body::Pong {
+                    version: frame::VERSION,
+                },
+            };
+            write_all(class, framer.encode(&pong)).await?;
+            Ok(true)
+        }
+        Op::Console => {
+            info!("text mode");
+            let ok = Response {
+                id,
+                body: Body::Ok(""),
+            };
+            write_all(class, framer.encode(&ok)).await?;
+            Ok(false)
+        }
+    }
+}
+
+/// Commands about the link itself are answered here, the rest by the
+/// controller. `None` when the reply comes later, from `Client::reply`.
+fn dispatch(
+    cmd: Cmd,
+    to: ReplyTo,
+    events: &mut Option<Events>,
+    client: &mut Link,
+) -> Option<Reply> {
+    match cmd {
+        Cmd::Subscribe => {
+            if events.is_none() {
+                *events = event::subscribe();
+            }
+            Some(match events {
+                Some(_) => Ok(Answer::default()),
+                None => Err(CmdError::new(ErrorCode::Full, "no subscriber slot left")),
+            })
+        }
+        Cmd::Unsubscribe => {
+            *events = None;
+            Some(Ok(Answer::default()))
+        }
+        cmd => client.submit(cmd, to).err().map(Err),
+    }
+}
+
+/// Write the reply to a command in the form the command came in.
+async fn answer<'d, T: Instance + 'd>(
+    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+    framer: &mut Framer,
+    line: &mut [u8; json::MAX_LINE],
+    to: ReplyTo,
+    reply: Reply,
+) -> Result<(), Disconnected> {
+    match to {
+        ReplyTo::Text => {
+            let mut status = String::<80>::new();
+            match reply {
+                Ok(answer) => {
+                    for c in answer.into_text().as_bytes() {
+                        if *c == b'\n' {
+                            class.write_packet(&[b'\r', b'\n']).await?;
+                        } else {
+                            class.write_packet(&[*c]).await?;
+                        }
+                    }
+                    status.push_str("[OK]").ok();
+                }
+                Err(err) => {
+                    write!(status, "{}", err).ok();
+                }
+            }
+            status.push_str("\n\r").ok();
+            write_all(class, status.as_bytes()).await
+        }
+        ReplyTo::Json => {
+            let n = json::encode(&reply, line);
+            write_all(class, &line[..n]).await
+        }
+        ReplyTo::Frame(id) => {
+            let response = Response {
+                id,
+                body: Body::reply(&reply),
+            };
+            write_all(class, framer.encode(&response)).await
+        }
+    }
+}
+
+/// The next event for a subscribed link, never for the others.
+async fn next_event(events: &mut Option<Events>) -> Event {
+    match events {
+        Some(events) => events.next_message_pure().await,
+        None => core::future::pending().await,
+    }
+}
+
+/// Write `data` in packets of at most 64 bytes.
+async fn write_all<'d, T: Instance + 'd>(
+    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+    data: &[u8],
+) -> Result<(), Disconnected> {
+    for chunk in data.chunks(64) {
+        class.write_packet(chunk).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,409 @@
+//! Host simulation backends.
+//!
+//! The firmware is built for the host with the pins, EEPROM and USB serial
+//! replaced by the types below, so whole watering cycles can run on a laptop:
+//!
+//! ```text
+//! printf 'sleep 4000\nstop\nadd pos 10 20 5 200\nstart\nsleep 3000\nstop\n' | cargo sim
+//! ```
+//!
+//! Every script line is a console command, except `sleep <ms>` which lets the
+//! schedule run for a while. After the last line the recorded step counts and
+//! pump on-time are printed. `tests/sim.rs` checks scripts like this one under
+//! `cargo sim-test`.
+
+use core::convert::Infallible;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, Ordering};
+use embassy_executor::Spawner;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use futures::future::{select, Either};
+
+use crate::clock::{Clock, Date, TimeOfDay};
+use crate::command::{Cmd, CmdError, ErrorCode};
+use crate::controller::{Answer, Client, Reply};
+use crate::event;
+use crate::{controller, pump::Pump, stepper::Stepper, storage::Storage};
+
+pub static DIR_X: PinLog = PinLog::new();
+pub static DIR_Y: PinLog = PinLog::new();
+pub static DIR_Z: PinLog = PinLog::new();
+pub static STEP_X: PinLog = PinLog::new();
+pub static STEP_Y: PinLog = PinLog::new();
+pub static STEP_Z: PinLog = PinLog::new();
+pub static PUMP: PinLog = PinLog::new();
+
+/// How far the simulated carriages start from their endstops.
+const SIM_HOME_STEPS: i32 = 200;
+
+const DAY_S: u64 = 24 * 3600;
+
+/// Everything that happened on one simulated output line.
+pub struct PinLog {
+    high: AtomicBool,
+    rising: AtomicU32,
+    steps: AtomicI32,
+    high_since: AtomicU64,
+    high_us: AtomicU64,
+}
+
+impl PinLog {
+    pub const fn new() -> Self {
+        Self {
+            high: AtomicBool::new(false),
+            rising: AtomicU32::new(0),
+            steps: AtomicI32::new(0),
+            high_since: AtomicU64::new(0),
+            high_us: AtomicU64::new(0),
+        }
+    }
+    pub fn is_high(&self) -> bool {
+        self.high.load(Ordering::Relaxed)
+    }
+    /// Number of low to high transitions.
+    pub fn rising(&self) -> u32 {
+        self.rising.load(Ordering::Relaxed)
+    }
+    /// Rising edges counted up or down by the paired direction line.
+    pub fn steps(&self) -> i32 {
+        self.steps.load(Ordering::Relaxed)
+    }
+    /// Total time spent high, including the current high period.
+    pub fn high_time(&self) -> Duration {
+        let mut us = self.high_us.load(Ordering::Relaxed);
+        if self.is_high() {
+            us += Instant::now().as_micros() - self.high_since.load(Ordering::Relaxed);
+        }
+        Duration::from_micros(us)
+    }
+}
+
+impl Default for PinLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct SimPin {
+    log: &'static PinLog,
+    dir: Option<&'static PinLog>,
+}
+
+impl SimPin {
+    pub fn new(log: &'static PinLog) -> Self {
+        Self { log, dir: None }
+    }
+    /// A step line that counts its pulses in the direction given by `dir`.
+    pub fn step(log: &'static PinLog, dir: &'static PinLog) -> Self {
+        Self {
+            log,
+            dir: Some(dir),
+        }
+    }
+}
+
+impl OutputPin for SimPin {
+    type Error = Infallible;
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        if !self.log.high.swap(true, Ordering::Relaxed) {
+            self.log.rising.fetch_add(1, Ordering::Relaxed);
+            self.log
+                .high_since
+                .store(Instant::now().as_micros(), Ordering::Relaxed);
+            if let Some(dir) = self.dir {
+                let delta = if dir.is_high() { 1 } else { -1 };
+                self.log.steps.fetch_add(delta, Ordering::Relaxed);
+            }
+        }
+        Ok(())
+    }
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        if self.log.high.swap(false, Ordering::Relaxed) {
+            let since = self.log.high_since.load(Ordering::Relaxed);
+            self.log
+                .high_us
+                .fetch_add(Instant::now().as_micros() - since, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+}
+
+/// Endstop that closes once the axis has stepped down to `at`.
+pub struct SimInput {
+    step: &'static PinLog,
+    at: i32,
+}
+
+impl SimInput {
+    pub fn endstop(step: &'static PinLog, at: i32) -> Self {
+        Self { step, at }
+    }
+}
+
+impl InputPin for SimInput {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        Ok(self.step.steps() > self.at)
+    }
+    fn is_low(&self) -> Result<bool, Infallible> {
+        Ok(self.step.steps() <= self.at)
+    }
+}
+
+#[derive(Debug)]
+pub struct SimI2cError;
+
+/// In-memory 24C64-style EEPROM answering at address 0x50.
+pub struct SimEeprom {
+    mem: [u8; 8192],
+    addr: usize,
+}
+
+impl SimEeprom {
+    /// A blank chip, every byte reads as 0xFF.
+    pub fn new() -> Self {
+        Self {
+            mem: [0xFF; 8192],
+            addr: 0,
+        }
+    }
+    fn set_addr(&mut self, bytes: &[u8]) -> Result<(), SimI2cError> {
+        match bytes {
+            [hi, lo, ..] => {
+                self.addr = u16::from_be_bytes([*hi, *lo]) as usize % self.mem.len();
+                Ok(())
+            }
+            _ => Err(SimI2cError),
+        }
+    }
+}
+
+impl Write for SimEeprom {
+    type Error = SimI2cError;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), SimI2cError> {
+        if address != 0x50 {
+            return Err(SimI2cError);
+        }
+        self.set_addr(bytes)?;
+        for b in &bytes[2..] {
+            self.mem[self.addr] = *b;
+            self.addr = (self.addr + 1) % self.mem.len();
+        }
+        Ok(())
+    }
+}
+
+impl WriteRead for SimEeprom {
+    type Error = SimI2cError;
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        bytes: &[u8],
+        buffer: &mut [u8],
+    ) -> Result<(), SimI2cError> {
+        if address != 0x50 {
+            return Err(SimI2cError);
+        }
+        self.set_addr(bytes)?;
+        for b in buffer.iter_mut() {
+            *b = self.mem[self.addr];
+            self.addr = (self.addr + 1) % self.mem.len();
+        }
+        Ok(())
+    }
+}
+
+impl Default for SimEeprom {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Time of day running off the host clock, starting at midnight on the epoch.
+pub struct SimClock {
+    offset_s: u64,
+    /// The date on day `day0` of the host clock.
+    date: Date,
+    day0: u64,
+}
+
+impl SimClock {
+    pub fn new() -> Self {
+        Self {
+            offset_s: 0,
+            date: Date::EPOCH,
+            day0: 0,
+        }
+    }
+    fn day(&self) -> u64 {
+        (Instant::now().as_secs() + self.offset_s) / DAY_S
+    }
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&mut self) -> Option<TimeOfDay> {
+        let s = (Instant::now().as_secs() + self.offset_s) % DAY_S;
+        TimeOfDay::new((s / 3600) as u8, (s / 60 % 60) as u8, (s % 60) as u8)
+    }
+    fn set(&mut self, time: TimeOfDay) -> Result<(), ()> {
+        let today = self.today().ok_or(())?;
+        let target = time.minute_of_day() as u64 * 60 + time.second as u64;
+        self.offset_s = (target + DAY_S - Instant::now().as_secs() % DAY_S) % DAY_S;
+        self.set_date(today)
+    }
+    fn today(&mut self) -> Option<Date> {
+        let mut date = self.date;
+        for _ in self.day0..self.day() {
+            date = date.next();
+        }
+        Some(date)
+    }
+    fn set_date(&mut self, date: Date) -> Result<(), ()> {
+        self.date = date;
+        self.day0 = self.day();
+        Ok(())
+    }
+}
+
+/// Wire the controller to simulated hardware and feed it the script on stdin.
+pub fn start(spawner: Spawner) {
+    let script = std::io::stdin().lines().map_while(Result::ok).collect();
+
+    spawner.must_spawn(controller::run(
+        Stepper::new(
+            SimPin::new(&DIR_X),
+            SimPin::step(&STEP_X, &DIR_X),
+            Some(SimInput::endstop(&STEP_X, -SIM_HOME_STEPS)),
+        ),
+        Stepper::new(
+            SimPin::new(&DIR_Y),
+            SimPin::step(&STEP_Y, &DIR_Y),
+            Some(SimInput::endstop(&STEP_Y, -SIM_HOME_STEPS)),
+        ),
+        Stepper::new(
+            SimPin::new(&DIR_Z),
+            SimPin::step(&STEP_Z, &DIR_Z),
+            Some(SimInput::endstop(&STEP_Z, -SIM_HOME_STEPS)),
+        ),
+        Storage::new(SimEeprom::new()),
+        Pump::new(SimPin::new(&PUMP)),
+        SimClock::new(),
+    ));
+    spawner.must_spawn(console(script));
+    spawner.must_spawn(events());
+}
+
+/// Set by `subscribe` in the script.
+static SUBSCRIBED: AtomicBool = AtomicBool::new(false);
+
+/// Prints the events the console subscribed to.
+#[embassy_executor::task]
+async fn events() {
+    let Some(mut events) = event::subscribe() else {
+        return;
+    };
+    loop {
+        let event = events.next_message_pure().await;
+        if SUBSCRIBED.load(Ordering::Relaxed) {
+            println!("[EVENT {}]", event);
+        }
+    }
+}
+
+/// Stands in for the USB serial console. Like the port it reads on while a
+/// command runs: the lines after it queue up and `sleep` counts from when it
+/// is read, so a `stop` can cut a move short. The report waits for every
+/// queued command.
+#[embassy_executor::task]
+async fn console(script: std::vec::Vec<std::string::String>) {
+    let mut client = Client::<(), 16>::new();
+    for line in script.iter().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        println!("> {}", line);
+        if let Some(ms) = line.strip_prefix("sleep ") {
+            let until = Instant::now() + Duration::from_millis(ms.trim().parse().unwrap_or(0));
+            while let Either::Right(((reply, ()), _)) =
+                select(pin!(Timer::at(until)), pin!(client.reply())).await
+            {
+                print_reply(reply);
+            }
+            continue;
+        }
+        let ret = match crate::command::parse_cmd(line) {
+            Ok((_, cmd @ (Cmd::Subscribe | Cmd::Unsubscribe))) => {
+                SUBSCRIBED.store(cmd == Cmd::Subscribe, Ordering::Relaxed);
+                Ok(Answer::default())
+            }
+            Ok((_, cmd)) => match client.submit(cmd, ()) {
+                Ok(()) => continue,
+                Err(err) => Err(err),
+            },
+            Err(_) => Err(CmdError::new(ErrorCode::Parse, "parse fail")),
+        };
+        print_reply(ret);
+    }
+    while !client.is_idle() {
+        let (reply, ()) = client.reply().await;
+        print_reply(reply);
+    }
+    report();
+    std::process::exit(0);
+}
+
+fn print_reply(reply: Reply) {
+    match reply {
+        Ok(text) => {
+            print!("{}", text);
+            println!("[OK]");
+        }
+        Err(err) => println!("{}", err),
+    }
+}
+
+pub fn report() {
+    for (name, step) in [("x", &STEP_X), ("y", &STEP_Y), ("z", &STEP_Z)] {
+        println!(
+            "{}: {} pulses, net {} steps",
+            name,
+            step.rising(),
+            step.steps()
+        );
+    }
+    println!(
+        "pump: switched on {} times, on for {} ms",
+        PUMP.rising(),
+        PUMP.high_time().as_millis()
+    );
+}
+
+/// Poll `f` until it is done, the timers run off the host clock.
+#[cfg(test)]
+pub fn block_on<F: core::future::Future>(f: F) -> F::Output {
+    block_on_with(f, || ())
+}
+
+/// Like `block_on`, calling `each` between polls.
+#[cfg(test)]
+pub fn block_on_with<F: core::future::Future>(f: F, mut each: impl FnMut()) -> F::Output {
+    use core::task::{Context, Poll, Waker};
+    let mut f = core::pin::pin!(f);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(out) = f.as_mut().poll(&mut cx) {
+            return out;
+        }
+        each();
+        std::thread::yield_now();
+    }
+}
@@ -0,0 +1,217 @@
+//! Step pulses generated from hardware timer interrupts.
+//!
+//! Every axis gets a general purpose timer counting at 1 MHz. `step_move`
+//! still computes the profile, but instead of sleeping between pulses it
+//! pushes each step interval into the axis queue and the update interrupt
+//! pulses the pin. USB traffic and other tasks then no longer show up as
+//! step jitter. Homing and `linear` keep pulsing the pin themselves.
+
+use core::cell::RefCell;
+use core::convert::Infallible;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use embassy_stm32::gpio::{AnyPin, Output};
+use embassy_stm32::interrupt::{self, InterruptExt};
+use embassy_stm32::pac::{self, timer::vals::Urs, timer::TimGp16};
+use embassy_stm32::peripherals::{TIM3, TIM4, TIM5};
+use embassy_stm32::rcc::low_level::RccPeripheral;
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex as Raw, Mutex};
+use embassy_sync::channel::Channel;
+use embassy_time::{Duration, Timer};
+use embedded_hal::digital::v2::OutputPin;
+
+use crate::stepper::{StepPin, ESTOP};
+
+/// Step intervals queued ahead of the pulses, a halt reacts this many steps
+/// late at most.
+const QUEUE_LEN: usize = 32;
+
+/// APB1 timer clock, twice `pclk1` as set up in `main`.
+const TIMER_CLOCK_MHZ: u16 = 48;
+
+/// High time of a step pulse.
+const PULSE_US: u32 = 10;
+
+static AXES: [TimerAxis; 3] = [
+    TimerAxis::new(pac::TIM3),
+    TimerAxis::new(pac::TIM4),
+    // TIM5 is a 32 bit timer, its 16 bit view is what embassy's own timer
+    // drivers use: the registers touched here sit at the same offsets.
+    TimerAxis::new(unsafe { TimGp16::from_ptr(pac::TIM5.as_ptr()) }),
+];
+
+/// Step queue and timer of one axis.
+pub struct TimerAxis {
+    regs: TimGp16,
+    queue: Channel<Raw, u32, QUEUE_LEN>,
+    pin: Mutex<Raw, RefCell<Option<Output<'static, AnyPin>>>>,
+    /// Timer running, cleared by the interrupt once the queue is empty.
+    busy: AtomicBool,
+    /// Pin is high, the next update ends the pulse.
+    pulse_high: AtomicBool,
+    /// Low time left of the current step, beyond the running reload.
+    rest_us: AtomicU32,
+    /// Pulses started since the last `flush`.
+    emitted: AtomicU32,
+}
+
+impl TimerAxis {
+    const fn new(regs: TimGp16) -> Self {
+        Self {
+            regs,
+            queue: Channel::new(),
+            pin: Mutex::new(RefCell::new(None)),
+            busy: AtomicBool::new(false),
+            pulse_high: AtomicBool::new(false),
+            rest_us: AtomicU32::new(0),
+            emitted: AtomicU32::new(0),
+        }
+    }
+
+    /// Queue a step `period_us` after the previous one, waiting while the
+    /// queue is full.
+    pub async fn push(&self, period_us: u32) {
+        self.queue.send(period_us).await;
+        // The interrupt only clears `busy` after finding the queue empty, so
+        // the step just sent is either picked up or restarts the timer here.
+        if !self.busy.swap(true, Ordering::AcqRel) {
+            self.start();
+        }
+    }
+
+    /// Wait until every queued step has been pulsed out. Returns the number
+    /// of pulses emitted since the previous flush, an emergency stop drops
+    /// the rest of the queue.
+    pub async fn flush(&self) -> u32 {
+        while self.busy.load(Ordering::Acquire) {
+            Timer::after(Duration::from_millis(1)).await;
+        }
+        self.emitted.swap(0, Ordering::Relaxed)
+    }
+
+    fn start(&self) {
+        self.regs.cnt().write(|w| w.set_cnt(0));
+        self.set_arr(1);
+        self.regs.cr1().modify(|w| w.set_cen(true));
+    }
+
+    fn set_arr(&self, us: u32) {
+        let ticks = us.clamp(1, u16::MAX as u32) as u16;
+        self.regs.arr().write(|w| w.set_arr(ticks));
+    }
+
+    /// Wait `us` before the next update, in as many reloads as it takes.
+    fn wait(&self, us: u32) {
+        let reload = us.min(u16::MAX as u32);
+        self.rest_us.store(us - reload, Ordering::Relaxed);
+        self.set_arr(reload);
+    }
+
+    fn set_pin(&self, high: bool) {
+        self.pin.lock(|pin| {
+            if let Some(pin) = pin.borrow_mut().as_mut() {
+                if high {
+                    pin.set_high();
+                } else {
+                    pin.set_low();
+                }
+            }
+        });
+    }
+
+    /// Update interrupt: end the running pulse, carry on a long low time or
+    /// start the next queued step.
+    fn on_update(&self) {
+        self.regs.sr().modify(|w| w.set_uif(false));
+        if self.pulse_high.swap(false, Ordering::Relaxed) {
+            self.set_pin(false);
+            self.wait(self.rest_us.load(Ordering::Relaxed));
+            return;
+        }
+        let estop = ESTOP.load(Ordering::Relaxed);
+        let rest = self.rest_us.load(Ordering::Relaxed);
+        if rest > 0 && !estop {
+            self.wait(rest);
+            return;
+        }
+        if estop {
+            self.rest_us.store(0, Ordering::Relaxed);
+            while self.queue.try_receive().is_ok() {}
+        }
+        match self.queue.try_receive() {
+            Ok(period_us) => {
+                self.set_pin(true);
+                self.emitted.fetch_add(1, Ordering::Relaxed);
+                self.pulse_high.store(true, Ordering::Relaxed);
+                self.rest_us
+                    .store(period_us.saturating_sub(PULSE_US), Ordering::Relaxed);
+                self.set_arr(PULSE_US);
+            }
+            Err(_) => {
+                self.regs.cr1().modify(|w| w.set_cen(false));
+                self.busy.store(false, Ordering::Release);
+            }
+        }
+    }
+}
+
+/// Step pin owned by a timer axis.
+pub struct TimerStepPin(&'static TimerAxis);
+
+impl OutputPin for TimerStepPin {
+    type Error = Infallible;
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.0.set_pin(true);
+        Ok(())
+    }
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.0.set_pin(false);
+        Ok(())
+    }
+}
+
+impl StepPin for TimerStepPin {
+    fn timer(&self) -> Option<&'static TimerAxis> {
+        Some(self.0)
+    }
+}
+
+/// Hand the x, y and z step pins to TIM3, TIM4 and TIM5.
+pub fn init(_timers: (TIM3, TIM4, TIM5), pins: [Output<'static, AnyPin>; 3]) -> [TimerStepPin; 3] {
+    TIM3::enable();
+    TIM4::enable();
+    TIM5::enable();
+    for (axis, pin) in AXES.iter().zip(pins) {
+        axis.pin.lock(|p| p.replace(Some(pin)));
+        let regs = axis.regs;
+        regs.psc().write(|w| w.set_psc(TIMER_CLOCK_MHZ - 1));
+        // Only counter overflows raise the interrupt, not the `ug` below.
+        regs.cr1().write(|w| w.set_urs(Urs::COUNTERONLY));
+        regs.egr().write(|w| w.set_ug(true));
+        regs.dier().write(|w| w.set_uie(true));
+    }
+    for irq in [interrupt::TIM3, interrupt::TIM4, interrupt::TIM5] {
+        irq.unpend();
+        unsafe { irq.enable() };
+    }
+    [
+        TimerStepPin(&AXES[0]),
+        TimerStepPin(&AXES[1]),
+        TimerStepPin(&AXES[2]),
+    ]
+}
+
+#[interrupt]
+fn TIM3() {
+    AXES[0].on_update();
+}
+
+#[interrupt]
+fn TIM4() {
+    AXES[1].on_update();
+}
+
+#[interrupt]
+fn TIM5() {
+    AXES[2].on_update();
+}
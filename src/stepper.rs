@@ -1,60 +1,309 @@
-use defmt::info;
-use embassy_stm32::gpio::{AnyPin, Output};
+use core::sync::atomic::{AtomicBool, Ordering};
 use embassy_time::{Duration, Timer};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use serde::{Deserialize, Serialize};
 
-pub struct Stepper<'a> {
-    dir_pin: Output<'a, AnyPin>,
-    step_pin: Output<'a, AnyPin>,
-    current_pos: i32,
+use crate::profile::Profile;
+
+/// Set to bring every axis to a controlled halt, moves in progress
+/// decelerate to `speed_min` and return early. Cleared by the controller once
+/// it has handled the stop.
+pub static HALT: AtomicBool = AtomicBool::new(false);
+
+/// Set by an emergency stop, step pulses stop at once without decelerating.
+/// Stays latched until the controller is reset.
+pub static ESTOP: AtomicBool = AtomicBool::new(false);
+
+fn halted() -> bool {
+    HALT.load(Ordering::Relaxed) || ESTOP.load(Ordering::Relaxed)
+}
+
+fn estopped() -> bool {
+    ESTOP.load(Ordering::Relaxed)
+}
+
+/// Output pin that step pulses are sent to.
+///
+/// With the `timer-steps` feature a step pin can hand its pulses to a
+/// hardware timer queue, otherwise `step_move` pulses it from the executor.
+pub trait StepPin: OutputPin {
+    #[cfg(all(target_os = "none", feature = "timer-steps"))]
+    fn timer(&self) -> Option<&'static crate::step_timer::TimerAxis> {
+        None
+    }
+}
+
+/// Motion parameters of one axis that are kept in the EEPROM.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AxisConfig {
+    pub step_per_mm: u32,
+    pub speed_min: u32,
+    pub speed_max: u32,
+    pub speed_accel: u32,
+    /// mm/s^3, zero moves along a plain trapezoid.
+    pub speed_jerk: u32,
+}
+
+impl Default for AxisConfig {
+    fn default() -> Self {
+        Self {
+            step_per_mm: 20,
+            speed_min: 10,
+            speed_max: 250,
+            speed_accel: 50,
+            speed_jerk: 0,
+        }
+    }
+}
+
+/// `AxisConfig` as stored before the jerk limit existed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AxisConfigV1 {
+    pub step_per_mm: u32,
+    pub speed_min: u32,
+    pub speed_max: u32,
+    pub speed_accel: u32,
+}
+
+impl From<AxisConfigV1> for AxisConfig {
+    fn from(c: AxisConfigV1) -> Self {
+        Self {
+            step_per_mm: c.step_per_mm,
+            speed_min: c.speed_min,
+            speed_max: c.speed_max,
+            speed_accel: c.speed_accel,
+            speed_jerk: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum HomeError {
+    /// The switch did not trigger within the homing travel.
+    EndstopNotFound,
+    /// The switch is still pressed after backing off.
+    EndstopStuck,
+    /// Homing was interrupted by a stop.
+    Halted,
+}
+
+pub struct Stepper<D, S, E> {
+    dir_pin: D,
+    step_pin: S,
+    /// Limit switch at the home end of the axis, pulled up and active low.
+    endstop: Option<E>,
+    /// Step pulses emitted since homing, counted up in the positive
+    /// direction. Positions, offsets and limits are in micrometers.
+    step_pos: i32,
+    /// Cleared when the position can no longer be trusted.
+    homed: bool,
     step_per_mm: u32,
     speed_min: u32,
     speed_max: u32,
     speed_accel: u32,
+    speed_jerk: u32,
+    home_positive: bool,
+    home_offset: i32,
+    home_speed: u32,
+    /// Whole millimeters, like the speeds.
+    home_backoff: u32,
+    home_travel: u32,
+    travel_min: i32,
+    travel_max: i32,
 }
 
-impl<'a> Stepper<'a> {
-    pub fn new(dir_pin: Output<'a, AnyPin>, step_pin: Output<'a, AnyPin>) -> Self {
+impl<D: OutputPin, S: StepPin, E: InputPin> Stepper<D, S, E> {
+    pub fn new(dir_pin: D, step_pin: S, endstop: Option<E>) -> Self {
+        let config = AxisConfig::default();
         Stepper {
             dir_pin,
             step_pin,
-            current_pos: 0,
-            step_per_mm: 20,
-            speed_min: 10,
-            speed_max: 250,
-            speed_accel: 50,
+            endstop,
+            step_pos: 0,
+            homed: false,
+            step_per_mm: config.step_per_mm,
+            speed_min: config.speed_min,
+            speed_max: config.speed_max,
+            speed_accel: config.speed_accel,
+            speed_jerk: config.speed_jerk,
+            home_positive: false,
+            home_offset: 0,
+            home_speed: 20,
+            home_backoff: 5,
+            home_travel: 1000,
+            travel_min: -1_000_000,
+            travel_max: 1_000_000,
         }
     }
+
+    /// Step number of the position `um`, rounded to the nearest step.
+    fn to_steps(&self, um: i32) -> i64 {
+        let scaled = um as i64 * self.step_per_mm as i64;
+        (scaled + scaled.signum() * 500) / 1000
+    }
+
+    /// Position of step number `steps` in micrometers, rounded.
+    fn to_um(&self, steps: i32) -> i32 {
+        let spm = self.step_per_mm.max(1) as i64;
+        let scaled = steps as i64 * 1000;
+        ((scaled + scaled.signum() * spm / 2) / spm) as i32
+    }
+
+    /// Move to the step nearest to `pos`. A halt cuts the move short, the
+    /// position is then counted from the steps actually made.
     pub async fn goto(&mut self, pos: i32) {
-        let diff = pos - self.current_pos;
+        let diff = self.to_steps(pos) - self.step_pos as i64;
         match diff {
-            i32::MIN..=-1 => {
-                self.dir_pin.set_low();
+            i64::MIN..=-1 => {
+                self.dir_pin.set_low().ok();
             }
-            1..=i32::MAX => {
-                self.dir_pin.set_high();
+            1..=i64::MAX => {
+                self.dir_pin.set_high().ok();
             }
             _ => (),
         }
-        step_move(
+        let steps = diff.unsigned_abs() as u32;
+        let done = step_move(
             &mut self.step_pin,
-            diff.unsigned_abs() * self.step_per_mm,
+            steps,
             self.speed_min * self.step_per_mm,
             self.speed_max * self.step_per_mm,
             self.speed_accel * self.step_per_mm,
+            self.speed_jerk * self.step_per_mm,
         )
         .await;
-        self.current_pos = pos;
+        self.step_pos += diff.signum() as i32 * done as i32;
     }
+
     pub async fn r#move(&mut self, distance: i32) {
-        self.goto(self.current_pos + distance).await;
+        self.goto(self.current_pos() + distance).await;
     }
 
+    /// Seek the endstop fast, back off, seek it again slowly and take the
+    /// switch position as `home_offset`. Without an endstop the current
+    /// position is simply taken as home.
+    pub async fn home(&mut self) -> Result<(), HomeError> {
+        if self.endstop.is_some() {
+            let toward = self.home_positive;
+            let travel = self.home_travel * self.step_per_mm;
+            let backoff = self.home_backoff * self.step_per_mm;
+            let found = self.seek(toward, self.home_speed, travel, true).await;
+            if halted() {
+                return Err(HomeError::Halted);
+            } else if !found {
+                return Err(HomeError::EndstopNotFound);
+            }
+            self.seek(!toward, self.speed_min, backoff, false).await;
+            if halted() {
+                return Err(HomeError::Halted);
+            } else if self.endstop_triggered() {
+                return Err(HomeError::EndstopStuck);
+            }
+            let found = self.seek(toward, self.speed_min, backoff * 2, true).await;
+            if halted() {
+                return Err(HomeError::Halted);
+            } else if !found {
+                return Err(HomeError::EndstopNotFound);
+            }
+        }
+        info!("homed at {}", self.home_offset);
+        self.step_pos = self.to_steps(self.home_offset) as i32;
+        self.homed = true;
+        Ok(())
+    }
+
+    pub fn homed(&self) -> bool {
+        self.homed
+    }
+
+    /// Forget the position, e.g. after pulses were cut without deceleration.
+    pub fn invalidate_home(&mut self) {
+        self.homed = false;
+    }
+
+    /// Step at a constant speed, stopping early at the endstop if
+    /// `until_endstop` is set or on a halt. Returns whether the endstop is
+    /// triggered.
+    async fn seek(&mut self, positive: bool, speed: u32, steps: u32, until_endstop: bool) -> bool {
+        if positive {
+            self.dir_pin.set_high().ok();
+        } else {
+            self.dir_pin.set_low().ok();
+        }
+        let sps = (speed * self.step_per_mm).max(1);
+        let period = Duration::from_micros(1_000_000 / sps as u64);
+        let dir = if positive { 1 } else { -1 };
+        for _ in 0..steps {
+            if halted() || (until_endstop && self.endstop_triggered()) {
+                break;
+            }
+            self.step_pin.set_high().ok();
+            self.step_pos += dir;
+            Timer::after(Duration::from_micros(10)).await;
+            self.step_pin.set_low().ok();
+            Timer::after(period).await;
+        }
+        self.endstop_triggered()
+    }
+
+    pub fn endstop_triggered(&self) -> bool {
+        self.endstop
+            .as_ref()
+            .is_some_and(|e| e.is_low().unwrap_or(false))
+    }
+
+    /// Position in micrometers, derived from `step_pos`.
     pub fn current_pos(&self) -> i32 {
-        self.current_pos
+        self.to_um(self.step_pos)
     }
 
     pub fn set_current_pos(&mut self, current_pos: i32) {
-        self.current_pos = current_pos;
+        self.step_pos = self.to_steps(current_pos) as i32;
+    }
+
+    pub fn step_pos(&self) -> i32 {
+        self.step_pos
+    }
+
+    /// Whether `pos` lies inside the soft travel limits.
+    pub fn in_travel(&self, pos: i32) -> bool {
+        (self.travel_min..=self.travel_max).contains(&pos)
+    }
+
+    pub fn travel_min(&self) -> i32 {
+        self.travel_min
+    }
+
+    pub fn set_travel_min(&mut self, travel_min: i32) {
+        info!("travel min from {} to {}", self.travel_min(), travel_min);
+        self.travel_min = travel_min;
+    }
+    pub fn travel_max(&self) -> i32 {
+        self.travel_max
+    }
+
+    pub fn set_travel_max(&mut self, travel_max: i32) {
+        info!("travel max from {} to {}", self.travel_max(), travel_max);
+        self.travel_max = travel_max;
+    }
+
+    pub fn config(&self) -> AxisConfig {
+        AxisConfig {
+            step_per_mm: self.step_per_mm,
+            speed_min: self.speed_min,
+            speed_max: self.speed_max,
+            speed_accel: self.speed_accel,
+            speed_jerk: self.speed_jerk,
+        }
+    }
+
+    pub fn set_config(&mut self, config: AxisConfig) {
+        self.set_step_per_mm(config.step_per_mm);
+        self.set_speed_min(config.speed_min);
+        self.set_speed_max(config.speed_max);
+        self.set_speed_accel(config.speed_accel);
+        self.set_speed_jerk(config.speed_jerk);
     }
 
     pub fn speed_max(&self) -> u32 {
@@ -81,53 +330,284 @@ impl<'a> Stepper<'a> {
         info!("speed min from {} to {}", self.speed_accel(), speed_accel);
         self.speed_accel = speed_accel;
     }
+    pub fn speed_jerk(&self) -> u32 {
+        self.speed_jerk
+    }
+
+    pub fn set_speed_jerk(&mut self, speed_jerk: u32) {
+        info!("speed jerk from {} to {}", self.speed_jerk(), speed_jerk);
+        self.speed_jerk = speed_jerk;
+    }
+    pub fn home_positive(&self) -> bool {
+        self.home_positive
+    }
+
+    pub fn set_home_positive(&mut self, home_positive: bool) {
+        info!(
+            "home positive from {} to {}",
+            self.home_positive(),
+            home_positive
+        );
+        self.home_positive = home_positive;
+    }
+    pub fn home_offset(&self) -> i32 {
+        self.home_offset
+    }
+
+    pub fn set_home_offset(&mut self, home_offset: i32) {
+        info!("home offset from {} to {}", self.home_offset(), home_offset);
+        self.home_offset = home_offset;
+    }
+    pub fn home_speed(&self) -> u32 {
+        self.home_speed
+    }
+
+    pub fn set_home_speed(&mut self, home_speed: u32) {
+        info!("home speed from {} to {}", self.home_speed(), home_speed);
+        self.home_speed = home_speed;
+    }
     pub fn step_per_mm(&self) -> u32 {
         self.step_per_mm
     }
 
     pub fn set_step_per_mm(&mut self, step_per_mm: u32) {
         info!("step per mm from {} to {}", self.step_per_mm(), step_per_mm);
+        // Keep the position in millimeters, the step count is rescaled.
+        let pos = self.current_pos();
         self.step_per_mm = step_per_mm;
+        self.set_current_pos(pos);
+    }
+}
+
+/// Move three axes to `target` together along a straight line.
+///
+/// A single profile is planned along the path, its speed, acceleration and
+/// jerk limited so no axis exceeds its own settings. The axis with
+/// the most steps sets the pace and the others follow it Bresenham style.
+/// `feed` additionally caps the path speed in mm/s.
+pub async fn linear<D: OutputPin, S: StepPin, E: InputPin>(
+    x: &mut Stepper<D, S, E>,
+    y: &mut Stepper<D, S, E>,
+    z: &mut Stepper<D, S, E>,
+    target: [i32; 3],
+    feed: Option<f32>,
+) {
+    let mut axes = [x, y, z];
+    let mut diff = [0i64; 3];
+    let mut steps = [0u32; 3];
+    let mut dir = [0i32; 3];
+    for (i, axis) in axes.iter_mut().enumerate() {
+        diff[i] = target[i] as i64 - axis.current_pos() as i64;
+        let delta = axis.to_steps(target[i]) - axis.step_pos as i64;
+        if delta < 0 {
+            axis.dir_pin.set_low().ok();
+        } else {
+            axis.dir_pin.set_high().ok();
+        }
+        steps[i] = delta.unsigned_abs() as u32;
+        dir[i] = delta.signum() as i32;
+    }
+    let lead = steps.iter().copied().max().unwrap_or(0);
+    if lead == 0 {
+        return;
+    }
+
+    // Path limits in mm/s, mm/s^2 and mm/s^3, then in steps of the lead
+    // axis. A jerk of zero is no limit at all.
+    let sq: u128 = diff
+        .iter()
+        .map(|d| d.unsigned_abs() as u128)
+        .map(|d| d * d)
+        .sum();
+    let path = isqrt(sq) as f32 / 1000.0;
+    let (mut v_min, mut v_max, mut a_max) = (f32::MAX, f32::MAX, f32::MAX);
+    let mut j_max = None::<f32>;
+    for (i, axis) in axes.iter().enumerate().filter(|(i, _)| diff[*i] != 0) {
+        let scale = path * 1000.0 / diff[i].unsigned_abs() as f32;
+        v_min = v_min.min(axis.speed_min as f32 * scale);
+        v_max = v_max.min(axis.speed_max as f32 * scale);
+        a_max = a_max.min(axis.speed_accel as f32 * scale);
+        if axis.speed_jerk > 0 {
+            let jerk = axis.speed_jerk as f32 * scale;
+            j_max = Some(j_max.map_or(jerk, |j| j.min(jerk)));
+        }
+    }
+    if let Some(feed) = feed {
+        v_max = v_max.min(feed);
+        v_min = v_min.min(v_max);
+    }
+    let k = lead as f32 / path;
+    let (min_sps, max_sps, accel) = (v_min * k, v_max * k, a_max * k);
+    let jerk = j_max.map_or(0.0, |j| j * k);
+
+    let mut profile = Profile::new(lead, min_sps, max_sps, accel).with_jerk(jerk);
+    let mut err = [0u32; 3];
+    while let Some(period_us) = profile.next() {
+        if estopped() {
+            break;
+        }
+        let mut due = [false; 3];
+        for (i, axis) in axes.iter_mut().enumerate() {
+            err[i] += steps[i];
+            if 2 * err[i] >= lead {
+                err[i] -= lead;
+                due[i] = true;
+                axis.step_pin.set_high().ok();
+                axis.step_pos += dir[i];
+            }
+        }
+        Timer::after(Duration::from_micros(10)).await;
+        for (i, axis) in axes.iter_mut().enumerate() {
+            if due[i] {
+                axis.step_pin.set_low().ok();
+            }
+        }
+        Timer::after(Duration::from_micros(period_us as u64)).await;
+        if halted() {
+            profile.stop();
+        }
     }
 }
 
+fn isqrt(n: u128) -> u128 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = n;
+    let mut y = (x + 1) / 2;
+    while y < x {
+        x = y;
+        y = (x + n / x) / 2;
+    }
+    x
+}
+
+/// Run `step` steps along a trapezoidal `Profile`, an S-curve when `jerk`
+/// is not zero.
+///
+/// Returns the number of steps made, fewer than `step` if halted or
+/// emergency stopped.
 pub async fn step_move(
-    step_pin: &mut Output<'_, AnyPin>,
+    step_pin: &mut impl StepPin,
     step: u32,
     min_sps: u32,
     max_sps: u32,
     accel: u32,
-) {
-    let mut sps = min_sps as f32;
+    jerk: u32,
+) -> u32 {
+    let mut profile =
+        Profile::new(step, min_sps as f32, max_sps as f32, accel as f32).with_jerk(jerk as f32);
     let mut step_count = 0;
-    let mid_step = step / 2;
-    while (sps < max_sps as f32) && step_count < mid_step {
-        let period = 1f32 / sps;
-        step_pin.set_high();
-        Timer::after(Duration::from_micros(10)).await;
-        step_pin.set_low();
-        Timer::after(Duration::from_micros((period * 1_000_000f32) as u64)).await;
-        sps += accel as f32 * period;
+    while let Some(period_us) = profile.next() {
+        // An emergency stop does not wait for the ramp.
+        if estopped() {
+            break;
+        }
+        pulse(step_pin, period_us as u64).await;
         step_count += 1;
+        // Once halted, ramp down to the start speed and stop there.
+        if halted() {
+            profile.stop();
+        }
     }
 
-    if step_count < mid_step {
-        let period = 1f32 / sps;
-        for _ in 0..step_count * 2 {
-            step_pin.set_high();
-            Timer::after(Duration::from_micros(10)).await;
-            step_pin.set_low();
-            Timer::after(Duration::from_micros((period * 1_000_000f32) as u64)).await;
-            step_count += 1;
-        }
+    // Queued steps are only counted once the timer has pulsed them out.
+    #[cfg(all(target_os = "none", feature = "timer-steps"))]
+    if let Some(timer) = step_pin.timer() {
+        step_count = timer.flush().await;
     }
-    while step_count < step {
-        let period = 1f32 / sps;
-        step_pin.set_high();
-        Timer::after(Duration::from_micros(10)).await;
-        step_pin.set_low();
-        Timer::after(Duration::from_micros((period * 1_000_000f32) as u64)).await;
-        sps -= accel as f32 * period;
-        step_count += 1;
+    step_count
+}
+
+/// One step pulse followed by the rest of its `period_us`.
+///
+/// A timer backed pin only queues the interval, the queue applies
+/// backpressure once it is full.
+async fn pulse(step_pin: &mut impl StepPin, period_us: u64) {
+    #[cfg(all(target_os = "none", feature = "timer-steps"))]
+    if let Some(timer) = step_pin.timer() {
+        timer.push(period_us as u32).await;
+        return;
+    }
+    step_pin.set_high().ok();
+    Timer::after(Duration::from_micros(10)).await;
+    step_pin.set_low().ok();
+    Timer::after(Duration::from_micros(period_us)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{block_on, block_on_with, PinLog, SimInput, SimPin};
+
+    /// `HALT` is shared by every axis, tests that move take turns.
+    static MOTION: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn goto_steps_the_mock_pins() {
+        let _motion = MOTION.lock().unwrap();
+        static DIR: PinLog = PinLog::new();
+        static STEP: PinLog = PinLog::new();
+        let mut axis = Stepper::new(
+            SimPin::new(&DIR),
+            SimPin::step(&STEP, &DIR),
+            None::<SimInput>,
+        );
+
+        block_on(axis.goto(10_000));
+        assert!(DIR.is_high());
+        assert_eq!(STEP.steps(), 200);
+        assert_eq!(axis.current_pos(), 10_000);
+
+        // Rounded to the nearest step, 50 um each.
+        block_on(axis.goto(7_480));
+        assert!(!DIR.is_high());
+        assert_eq!(STEP.steps(), 150);
+        assert_eq!(STEP.rising(), 250);
+        assert_eq!(axis.current_pos(), 7_500);
+        assert!(!STEP.is_high());
+    }
+
+    #[test]
+    fn home_stops_at_the_endstop() {
+        let _motion = MOTION.lock().unwrap();
+        static DIR: PinLog = PinLog::new();
+        static STEP: PinLog = PinLog::new();
+        let endstop = SimInput::endstop(&STEP, -100);
+        let mut axis = Stepper::new(SimPin::new(&DIR), SimPin::step(&STEP, &DIR), Some(endstop));
+
+        assert!(!axis.homed());
+        block_on(axis.home()).unwrap();
+        assert!(axis.homed());
+        assert!(axis.endstop_triggered());
+        assert_eq!(STEP.steps(), -100);
+        assert_eq!(axis.current_pos(), 0);
+    }
+
+    #[test]
+    fn halt_keeps_the_step_position() {
+        let _motion = MOTION.lock().unwrap();
+        static DIR: PinLog = PinLog::new();
+        static STEP: PinLog = PinLog::new();
+        let mut axis = Stepper::new(
+            SimPin::new(&DIR),
+            SimPin::step(&STEP, &DIR),
+            None::<SimInput>,
+        );
+
+        // Halt a 2000 step move a quarter of the way in.
+        block_on_with(axis.goto(100_000), || {
+            if STEP.rising() >= 500 {
+                HALT.store(true, Ordering::Relaxed);
+            }
+        });
+        HALT.store(false, Ordering::Relaxed);
+        let made = STEP.steps();
+        assert!((500..2000).contains(&made), "{} steps", made);
+        assert_eq!(axis.current_pos(), axis.to_um(made));
+
+        // Back home on the very step the move started from.
+        block_on(axis.goto(0));
+        assert_eq!(STEP.steps(), 0);
     }
 }
@@ -1,30 +1,82 @@
-use defmt::info;
-use embassy_stm32::{i2c::I2c, peripherals::I2C1};
+use embassy_time::{Duration, Timer};
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use serde::{de::DeserializeOwned, Serialize};
 
-pub struct Storage {
-    i2c: I2c<'static, I2C1>,
+/// Largest record `write_record` and `read_record` handle, in bytes.
+const RECORD_MAX: usize = 128;
+
+pub struct Storage<I> {
+    i2c: I,
+    /// Outcome of the latest `write_page`, `None` before the first one.
+    last_write: Option<Result<(), ()>>,
 }
 
-impl Storage {
-    pub fn new(i2c: I2c<'static, I2C1>) -> Self {
-        Self { i2c }
+impl<I: Write + WriteRead> Storage<I> {
+    pub fn new(i2c: I) -> Self {
+        Self {
+            i2c,
+            last_write: None,
+        }
     }
     pub fn write_page(&mut self, idx: u8, page: [u8; 32]) -> Result<(), ()> {
         info!("write page {}", idx);
         let mut buf = [0; 34];
         buf[..2].copy_from_slice(&((idx as u16) << 5).to_be_bytes());
         buf[2..].copy_from_slice(&page);
-        self.i2c.blocking_write(0x50, &buf[..]).map_err(|_| ())?;
+        let written = self.i2c.write(0x50, &buf[..]).map_err(|_| ());
+        self.last_write = Some(written);
+        written?;
         info!("write page {} success {:?}", idx, buf[2..]);
         Ok(())
     }
+    /// Whether the latest write of any record went through.
+    pub fn last_write(&self) -> Option<Result<(), ()>> {
+        self.last_write
+    }
     pub fn read_page(&mut self, idx: u8) -> Result<[u8; 32], ()> {
         info!("read page {}", idx);
         let mut buf = [0; 32];
         self.i2c
-            .blocking_write_read(0x50, &((idx as u16) << 5).to_be_bytes(), &mut buf[..])
+            .write_read(0x50, &((idx as u16) << 5).to_be_bytes(), &mut buf[..])
             .map_err(|_| ())?;
         info!("read page {} success {:?}", idx, buf);
         Ok(buf)
     }
+    /// Store `value` with postcard over consecutive pages starting at `first`.
+    pub async fn write_record<T: Serialize>(&mut self, first: u8, value: &T) -> Result<(), ()> {
+        let mut buf = [0; RECORD_MAX];
+        let used = postcard::to_slice(value, &mut buf).map_err(|_| ())?.len();
+        for (idx, chunk) in buf[..used].chunks(32).enumerate() {
+            let mut page = [0; 32];
+            page[..chunk.len()].copy_from_slice(chunk);
+            self.write_page(first + idx as u8, page)?;
+            Timer::after(Duration::from_millis(10)).await;
+        }
+        Ok(())
+    }
+    /// Read back a record written by `write_record` spanning at most `pages`.
+    pub fn read_record<T: DeserializeOwned>(&mut self, first: u8, pages: u8) -> Result<T, ()> {
+        let mut buf = [0; RECORD_MAX];
+        for (idx, chunk) in buf.chunks_mut(32).take(pages as usize).enumerate() {
+            chunk.copy_from_slice(&self.read_page(first + idx as u8)?);
+        }
+        postcard::from_bytes(&buf).map_err(|_| ())
+    }
+}
+
+pub const CRC_INIT: u16 = 0xFFFF;
+
+/// CRC-16/CCITT-FALSE, start from `CRC_INIT` and feed the data in order.
+pub fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
 }
@@ -0,0 +1,137 @@
+//! Whole watering cycles on the host simulation, run by `cargo sim-test`.
+//!
+//! Each test feeds a console script to the simulation binary and checks the
+//! step counts and pump on-time it reports after the last line.
+#![cfg(not(target_os = "none"))]
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Steps per millimeter of every axis until configured otherwise.
+const STEP_PER_MM: i32 = 20;
+
+/// Scripts wait this long before their first command: a stop is taken as
+/// soon as it is read, and would cut the homing on power up short.
+const BOOT: &str = "sleep 4000\n";
+
+/// What `sim::report` printed, plus every console line before it.
+struct Run {
+    output: String,
+    /// Net steps of x, y and z.
+    net: [i32; 3],
+    /// Step pulses of x, y and z.
+    pulses: [u32; 3],
+    pump_on: u32,
+    pump_ms: u64,
+}
+
+impl Run {
+    fn count(&self, line: &str) -> usize {
+        self.output.lines().filter(|l| *l == line).count()
+    }
+}
+
+fn run(script: &str) -> Run {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_serial"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("simulation binary");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(script.as_bytes())
+        .unwrap();
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+    let output = String::from_utf8(out.stdout).unwrap();
+
+    let mut net = [0; 3];
+    let mut pulses = [0; 3];
+    let (mut pump_on, mut pump_ms) = (None, None);
+    for line in output.lines() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [axis @ ("x:" | "y:" | "z:"), p, "pulses,", "net", n, "steps"] => {
+                let i = (axis.as_bytes()[0] - b'x') as usize;
+                pulses[i] = p.parse().unwrap();
+                net[i] = n.parse().unwrap();
+            }
+            ["pump:", "switched", "on", n, "times,", "on", "for", ms, "ms"] => {
+                pump_on = Some(n.parse().unwrap());
+                pump_ms = Some(ms.parse().unwrap());
+            }
+            _ => (),
+        }
+    }
+    Run {
+        net,
+        pulses,
+        pump_on: pump_on.expect("report printed"),
+        pump_ms: pump_ms.unwrap(),
+        output,
+    }
+}
+
+#[test]
+fn watering_cycle_start_stop() {
+    // Only homes, the schedule comes up enabled but has nothing to water.
+    let homed = run(&format!("{BOOT}stop\n"));
+    assert_eq!(homed.pump_on, 0);
+
+    // The long repeat duration puts the stop between the first and the
+    // second cycle, and the last sleep gives a second cycle time to show.
+    let run = run(&format!(
+        "{BOOT}stop\n\
+         subscribe\n\
+         repeat duration 3000\n\
+         add pos 10 20 5 200\n\
+         start\n\
+         sleep 6000\n\
+         stop\n\
+         sleep 3000\n"
+    ));
+    assert!(!run.output.contains("[ERR"), "{}", run.output);
+    assert_eq!(run.count("[EVENT cycle started]"), 1, "{}", run.output);
+    assert_eq!(run.count("[EVENT position 0 reached]"), 1);
+    assert_eq!(run.count("[EVENT cycle finished, 1 positions watered]"), 1);
+
+    // x and y stay over the position, z went down and back up.
+    let moved = [10 * STEP_PER_MM, 20 * STEP_PER_MM, 0];
+    for axis in 0..3 {
+        assert_eq!(
+            run.net[axis],
+            homed.net[axis] + moved[axis],
+            "axis {}",
+            axis
+        );
+    }
+    assert_eq!(run.pulses[2], homed.pulses[2] + 2 * 5 * STEP_PER_MM as u32);
+
+    assert_eq!(run.pump_on, 1);
+    assert!(
+        (190..=240).contains(&run.pump_ms),
+        "pump on for {} ms",
+        run.pump_ms
+    );
+}
+
+#[test]
+fn stop_cuts_the_cycle_short() {
+    let run = run(&format!(
+        "{BOOT}stop\n\
+         add pos 10 20 5 5000\n\
+         start\n\
+         sleep 3500\n\
+         stop\n"
+    ));
+    // The pump came on and was switched off by the stop, long before the
+    // 5 s watering time was up.
+    assert_eq!(run.pump_on, 1, "{}", run.output);
+    assert!(
+        (500..2000).contains(&run.pump_ms),
+        "pump on for {} ms",
+        run.pump_ms
+    );
+}